@@ -1,6 +1,15 @@
 use std::slice;
 use runtime::{LoxValue};
 
+pub const BYTECODE_MAGIC: &[u8] = b"rlox";
+const BYTECODE_VERSION: u8 = 1;
+
+const CONSTANT_NUMBER: u8 = 0;
+const CONSTANT_BOOLEAN: u8 = 1;
+const CONSTANT_NIL: u8 = 2;
+const CONSTANT_STRING: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instruction {
     Return,
     Constant(u8),
@@ -9,7 +18,23 @@ pub enum Instruction {
     Add,
     Subtract,
     Multiply,
-    Divide
+    Divide,
+    
+    // Converts the value on top of the stack to a string, for interpolation.
+    Stringify,
+    
+    // `!=`, `<=` and `>=` compile to the opposite comparison followed by Not.
+    Not,
+    Equal,
+    Greater,
+    Less,
+    
+    // Superinstructions produced by the peephole optimizer, each one standing
+    // in for a Constant immediately followed by the matching arithmetic.
+    AddConstant(u8),
+    SubtractConstant(u8),
+    MultiplyConstant(u8),
+    DivideConstant(u8)
 }
 
 impl Instruction {
@@ -25,7 +50,16 @@ impl Instruction {
             Instruction::Add => { },
             Instruction::Subtract => { },
             Instruction::Multiply => { },
-            Instruction::Divide => { }
+            Instruction::Divide => { },
+            Instruction::AddConstant(index) => bytecode.push(index),
+            Instruction::SubtractConstant(index) => bytecode.push(index),
+            Instruction::MultiplyConstant(index) => bytecode.push(index),
+            Instruction::DivideConstant(index) => bytecode.push(index),
+            Instruction::Stringify => { },
+            Instruction::Not => { },
+            Instruction::Equal => { },
+            Instruction::Greater => { },
+            Instruction::Less => { }
         };
         
         return bytecode;
@@ -41,19 +75,64 @@ impl Instruction {
             0 => (1, Some(Instruction::Return)),
             1 => {
                 let (bytes_consumed, operands) = Instruction::get_single_operand(bytecode);
-                (bytes_consumed + 1, operands.and_then(|o| Some(Instruction::Constant(o))))
+                (bytes_consumed + 1, operands.map(Instruction::Constant))
             },
             2 => (1, Some(Instruction::Negate)),
             3 => (1, Some(Instruction::Add)),
             4 => (1, Some(Instruction::Subtract)),
             5 => (1, Some(Instruction::Multiply)),
             6 => (1, Some(Instruction::Divide)),
+            7 => {
+                let (bytes_consumed, operands) = Instruction::get_single_operand(bytecode);
+                (bytes_consumed + 1, operands.map(Instruction::AddConstant))
+            },
+            8 => {
+                let (bytes_consumed, operands) = Instruction::get_single_operand(bytecode);
+                (bytes_consumed + 1, operands.map(Instruction::SubtractConstant))
+            },
+            9 => {
+                let (bytes_consumed, operands) = Instruction::get_single_operand(bytecode);
+                (bytes_consumed + 1, operands.map(Instruction::MultiplyConstant))
+            },
+            10 => {
+                let (bytes_consumed, operands) = Instruction::get_single_operand(bytecode);
+                (bytes_consumed + 1, operands.map(Instruction::DivideConstant))
+            },
+            11 => (1, Some(Instruction::Stringify)),
+            12 => (1, Some(Instruction::Not)),
+            13 => (1, Some(Instruction::Equal)),
+            14 => (1, Some(Instruction::Greater)),
+            15 => (1, Some(Instruction::Less)),
             _ => (1, None)
         };
         
         return instruction;
     }
 
+    // The index into the constant table of an instruction that uses one.
+    pub fn constant_index(&self) -> Option<u8> {
+        match *self {
+            Instruction::Constant(index) => Some(index),
+            Instruction::AddConstant(index) => Some(index),
+            Instruction::SubtractConstant(index) => Some(index),
+            Instruction::MultiplyConstant(index) => Some(index),
+            Instruction::DivideConstant(index) => Some(index),
+            _ => None
+        }
+    }
+
+    // The same instruction using another constant, for instructions that use one.
+    pub fn with_constant_index(&self, index: u8) -> Instruction {
+        match *self {
+            Instruction::Constant(_) => Instruction::Constant(index),
+            Instruction::AddConstant(_) => Instruction::AddConstant(index),
+            Instruction::SubtractConstant(_) => Instruction::SubtractConstant(index),
+            Instruction::MultiplyConstant(_) => Instruction::MultiplyConstant(index),
+            Instruction::DivideConstant(_) => Instruction::DivideConstant(index),
+            instruction => instruction
+        }
+    }
+
     fn get_opcode(&self) -> u8 {
         match self {
             Instruction::Return => 0,
@@ -62,7 +141,16 @@ impl Instruction {
             Instruction::Add => 3,
             Instruction::Subtract => 4,
             Instruction::Multiply => 5,
-            Instruction::Divide => 6
+            Instruction::Divide => 6,
+            Instruction::AddConstant(_) => 7,
+            Instruction::SubtractConstant(_) => 8,
+            Instruction::MultiplyConstant(_) => 9,
+            Instruction::DivideConstant(_) => 10,
+            Instruction::Stringify => 11,
+            Instruction::Not => 12,
+            Instruction::Equal => 13,
+            Instruction::Greater => 14,
+            Instruction::Less => 15
         }
     }
     
@@ -72,28 +160,25 @@ impl Instruction {
             None => (0, None)
         }
     }
-    
-    fn get_double_operands(bytecode: &mut slice::Iter<u8>) -> (usize, Option<(u8, u8)>) {
-        let &left = match bytecode.next() {
-            Some(operand) => operand,
-            None => return (0, None)
-        };
-        
-        let &right = match bytecode.next() {
-            Some(operand) => operand,
-            None => return (1, None)
-        };
-        
-        return (2, Some((left, right)));
-    }
 }
 
-
+#[derive(Clone)]
 pub struct Chunk {
     name: String,
     lines: Vec<usize>,
     code: Vec<u8>,
     constants: Vec<LoxValue>,
+    local_names: Vec<LocalName>
+}
+
+// Debug information naming the stack slot a local variable occupies while
+// the instructions in start..end execute.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LocalName {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize
 }
 
 impl Chunk {
@@ -102,7 +187,8 @@ impl Chunk {
             name: name.to_owned(),
             lines: Vec::new(),
             code: Vec::new(),
-            constants: Vec::new()
+            constants: Vec::new(),
+            local_names: Vec::new()
         }
     }
     
@@ -112,7 +198,7 @@ impl Chunk {
     }
     
     pub fn add_constant(&mut self, constant: LoxValue) -> Option<u8> {
-        if self.constants.len() >= (!0 as u8) as usize {
+        if self.constants.len() >= !0u8 as usize {
             return None;
         }
         
@@ -120,23 +206,265 @@ impl Chunk {
         return Some((self.constants.len() - 1) as u8);
     }
     
+    // Discards everything written after the given code offset and constant
+    // index, for the compiler to rewrite instructions it has just emitted.
+    pub fn truncate(&mut self, code_length: usize, constants_length: usize) {
+        self.lines.truncate(code_length);
+        self.code.truncate(code_length);
+        self.constants.truncate(constants_length);
+    }
+    
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+    
+    pub fn constants_len(&self) -> usize {
+        self.constants.len()
+    }
+    
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
     
-    pub fn code(&self) -> slice::Iter<u8> {
+    pub fn code(&self) -> slice::Iter<'_, u8> {
         self.code.iter()
     }
     
-    pub fn line(&self, offset: usize) -> usize {
-        self.lines[offset]
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.lines.get(offset).cloned()
+    }
+    
+    // The first line at or after the given one which has any code on it.
+    pub fn nearest_line_with_code(&self, line: usize) -> Option<usize> {
+        self.lines.iter().cloned().filter(|&l| l >= line).min()
     }
     
-    pub fn constant(&self, index: u8) -> &LoxValue {
-        &self.constants[index as usize]
+    pub fn add_local_name(&mut self, local: LocalName) {
+        self.local_names.push(local);
     }
     
-    pub fn constants(&self) -> slice::Iter<LoxValue> {
+    pub fn locals_at(&self, offset: usize) -> Vec<&LocalName> {
+        self.local_names.iter()
+            .filter(|local| local.start <= offset && offset < local.end)
+            .collect()
+    }
+    
+    // Only a verified chunk is sure to have every constant its code uses.
+    pub fn constant(&self, index: u8) -> Option<&LoxValue> {
+        self.constants.get(index as usize)
+    }
+    
+    pub fn constants(&self) -> slice::Iter<'_, LoxValue> {
         self.constants.iter()
     }
 }
+
+// A chunk of the given constants and instructions, each written on its line.
+#[cfg(test)]
+impl Chunk {
+    pub fn assemble(constants: &[LoxValue], instructions: &[(usize, Instruction)]) -> Chunk {
+        let mut chunk = Chunk::create("test");
+
+        for constant in constants {
+            chunk.add_constant(constant.clone());
+        }
+
+        for &(line, instruction) in instructions {
+            for byte in instruction.as_bytecode() {
+                chunk.write(line, byte);
+            }
+        }
+
+        return chunk;
+    }
+}
+
+// A compiled chunk on disk is the magic bytes and a version, then the name,
+// the constant table and the code with each byte's line. Every length and
+// line is a little endian u32.
+impl Chunk {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(BYTECODE_MAGIC);
+        bytes.push(BYTECODE_VERSION);
+        
+        write_u32(&mut bytes, self.name.len());
+        bytes.extend_from_slice(self.name.as_bytes());
+        
+        write_u32(&mut bytes, self.constants.len());
+        for constant in &self.constants {
+            if let Some(number) = constant.as_number() {
+                bytes.push(CONSTANT_NUMBER);
+                bytes.extend_from_slice(&number.to_bits().to_le_bytes());
+            } else if let Some(boolean) = constant.as_boolean() {
+                bytes.push(CONSTANT_BOOLEAN);
+                bytes.push(boolean as u8);
+            } else if constant.is_nil() {
+                bytes.push(CONSTANT_NIL);
+            } else if let Some(text) = constant.as_string() {
+                bytes.push(CONSTANT_STRING);
+                write_u32(&mut bytes, text.len());
+                bytes.extend_from_slice(text.as_bytes());
+            } else {
+                unreachable!("Only numbers, booleans, nil and strings can be constants");
+            }
+        }
+        
+        write_u32(&mut bytes, self.code.len());
+        for (byte, line) in self.code.iter().zip(self.lines.iter()) {
+            bytes.push(*byte);
+            write_u32(&mut bytes, *line);
+        }
+        
+        return bytes;
+    }
+    
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, &'static str> {
+        let mut reader = ByteReader { bytes, position: 0 };
+        
+        if reader.take(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+            return Err("Not a compiled Lox chunk");
+        }
+        
+        if reader.byte()? != BYTECODE_VERSION {
+            return Err("Unsupported bytecode version");
+        }
+        
+        let name_length = reader.u32()?;
+        let name = ::std::str::from_utf8(reader.take(name_length)?)
+            .map_err(|_| "Chunk name is not valid UTF-8")?;
+        let mut chunk = Chunk::create(name);
+        
+        let constant_count = reader.u32()?;
+        for _ in 0..constant_count {
+            let constant = match reader.byte()? {
+                CONSTANT_NUMBER => {
+                    let mut bits = [0u8; 8];
+                    bits.copy_from_slice(reader.take(8)?);
+                    LoxValue::number(f64::from_bits(u64::from_le_bytes(bits)))
+                },
+                CONSTANT_BOOLEAN => LoxValue::boolean(reader.byte()? != 0),
+                CONSTANT_NIL => LoxValue::nil(),
+                CONSTANT_STRING => {
+                    let length = reader.u32()?;
+                    let text = ::std::str::from_utf8(reader.take(length)?)
+                        .map_err(|_| "String constant is not valid UTF-8")?;
+                    LoxValue::string(text)
+                },
+                _ => return Err("Unknown constant type")
+            };
+            
+            if chunk.add_constant(constant).is_none() {
+                return Err("Too many constants in one chunk");
+            }
+        }
+        
+        let code_length = reader.u32()?;
+        for _ in 0..code_length {
+            let byte = reader.byte()?;
+            let line = reader.u32()?;
+            chunk.write(line, byte);
+        }
+        
+        if reader.position != bytes.len() {
+            return Err("Unexpected bytes after the end of the chunk");
+        }
+        
+        chunk.verify()?;
+        return Ok(chunk);
+    }
+    
+    // Checks that code which did not come from the compiler is safe to run
+    // and disassemble: every byte belongs to a complete, known instruction
+    // and every constant an instruction uses is in the table.
+    pub fn verify(&self) -> Result<(), &'static str> {
+        let mut code = self.code();
+        
+        while !code.as_slice().is_empty() {
+            let instruction = match Instruction::from_bytecode(&mut code) {
+                (_, Some(instruction)) => instruction,
+                (_, None) => return Err("Invalid instruction in chunk")
+            };
+            
+            if instruction.constant_index().is_some_and(|index| index as usize >= self.constants.len()) {
+                return Err("Constant index out of range");
+            }
+        }
+        
+        return Ok(());
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], &'static str> {
+        if self.bytes.len() - self.position < count {
+            return Err("Unexpected end of bytecode");
+        }
+        
+        let taken = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        return Ok(taken);
+    }
+    
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        self.take(1).map(|taken| taken[0])
+    }
+    
+    fn u32(&mut self) -> Result<usize, &'static str> {
+        let mut bits = [0u8; 4];
+        bits.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bits) as usize)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_round_trips_through_bytecode() {
+        let mut chunk = Chunk::create("round trip");
+        chunk.add_constant(LoxValue::number(-2.5));
+        chunk.add_constant(LoxValue::boolean(true));
+        chunk.add_constant(LoxValue::nil());
+        chunk.add_constant(LoxValue::string("caf\u{e9}"));
+        for byte in Instruction::Constant(0).as_bytecode() {
+            chunk.write(7, byte);
+        }
+        chunk.write(8, Instruction::Return.as_bytecode()[0]);
+
+        let restored = Chunk::deserialize(&chunk.serialize()).unwrap();
+
+        assert_eq!(restored.name(), "round trip");
+        assert_eq!(restored.code().cloned().collect::<Vec<u8>>(), chunk.code().cloned().collect::<Vec<u8>>());
+        assert_eq!((restored.line(0), restored.line(1), restored.line(2)), (Some(7), Some(7), Some(8)));
+        assert_eq!(restored.constant(0).unwrap().as_number(), Some(-2.5));
+        assert_eq!(restored.constant(1).unwrap().as_boolean(), Some(true));
+        assert!(restored.constant(2).unwrap().is_nil());
+        assert_eq!(restored.constant(3).unwrap().as_string(), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn chunk_rejects_truncated_bytecode() {
+        let mut chunk = Chunk::create("truncated");
+        chunk.add_constant(LoxValue::number(1.0));
+        let bytes = chunk.serialize();
+
+        for length in 0..bytes.len() {
+            assert!(Chunk::deserialize(&bytes[..length]).is_err());
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use compiler::OptimizationLevel;
+use debug::Format;
+use runtime::{DEFAULT_STACK_CAPACITY, MAX_STACK_CAPACITY};
+
+// Exit codes from sysexits.h, as used by clox.
+pub const EX_USAGE: i32 = 64;
+pub const EX_DATAERR: i32 = 65;
+pub const EX_NOINPUT: i32 = 66;
+pub const EX_SOFTWARE: i32 = 70;
+pub const EX_CANTCREAT: i32 = 73;
+pub const EX_IOERR: i32 = 74;
+
+pub const USAGE: &str = "\
+Usage: rlox [options] [path]
+       rlox run [options] <path>
+       rlox repl [options]
+       rlox compile [options] <path> [-o <output>]
+       rlox disasm [options] <path> [--format text|json]
+       rlox check [options] <path>...
+       rlox debug [options] <path>
+       rlox dap [options]
+       rlox lsp [options]
+       rlox fmt [--check] <path>...
+
+A path of - reads from standard input.
+
+Options:
+  -O0, -O1, -O2       optimization level, -O1 when not given; not for fmt
+
+These run the program, so they are for run, repl and debug only:
+  --trace             trace each instruction as it executes, as JSON lines
+  --trace-file <path> write the trace to a file instead of stderr
+  --trace-function <name>
+                      only trace instructions in the named function
+  --trace-lines <first>-<last>
+                      only trace instructions from the given source lines
+  --max-stack <n>     size of the value stack in slots, at most 1048576";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Input {
+    Stdin,
+    Path(String)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Run(Input),
+    Repl,
+    Compile { input: Input, output: Option<String> },
+    Disasm { input: Input, format: Format },
+    Check(Vec<Input>),
+    Debug(Input),
+    Dap,
+    Lsp,
+    Fmt { inputs: Vec<Input>, check: bool }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub optimization_level: OptimizationLevel,
+    pub trace: bool,
+    pub trace_file: Option<String>,
+    pub trace_function: Option<String>,
+    pub trace_lines: Option<(usize, usize)>,
+    pub max_stack: usize
+}
+
+// Parses everything after the program name.
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut optimization_level = OptimizationLevel::ConstantFolding;
+    let mut trace = false;
+    let mut trace_file = None;
+    let mut trace_function = None;
+    let mut trace_lines = None;
+    let mut max_stack = DEFAULT_STACK_CAPACITY;
+    let mut output = None;
+    let mut format = Format::Text;
+    let mut check = false;
+    let mut positional: Vec<&str> = Vec::new();
+    let mut flags: Vec<&str> = Vec::new();
+
+    let mut remaining = args.iter();
+    while let Some(arg) = remaining.next() {
+        if arg.starts_with('-') && arg != "-" {
+            flags.push(arg);
+        }
+
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--gc-stress" => return Err("--gc-stress is not supported, as there is no garbage collector yet".to_string()),
+            "--check" => check = true,
+            "--max-stack" => {
+                let value = remaining.next().ok_or("--max-stack needs a size")?;
+                max_stack = match value.parse() {
+                    Ok(size) if size > MAX_STACK_CAPACITY =>
+                        return Err(format!("Stack size '{}' is larger than the maximum of {}", value, MAX_STACK_CAPACITY)),
+                    Ok(size) if size > 0 => size,
+                    _ => return Err(format!("Invalid stack size '{}'", value))
+                };
+            },
+            "--trace-file" => {
+                let value = remaining.next().ok_or("--trace-file needs a path")?;
+                trace_file = Some(value.clone());
+            },
+            "--trace-function" => {
+                let value = remaining.next().ok_or("--trace-function needs a function name")?;
+                trace_function = Some(value.clone());
+            },
+            "--trace-lines" => {
+                let value = remaining.next().ok_or("--trace-lines needs a line range")?;
+                trace_lines = Some(line_range(value).ok_or(format!("Invalid line range '{}'", value))?);
+            },
+            "-o" => {
+                let value = remaining.next().ok_or("-o needs an output path")?;
+                output = Some(value.clone());
+            },
+            "--format" => {
+                format = match remaining.next().map(|value| value.as_str()) {
+                    Some("text") => Format::Text,
+                    Some("json") => Format::Json,
+                    Some(value) => return Err(format!("Unknown disassembly format '{}'", value)),
+                    None => return Err("--format needs a format".to_string())
+                };
+            },
+            flag if flag.starts_with("-O") => {
+                optimization_level = match flag[2..].parse().ok().and_then(OptimizationLevel::from_level) {
+                    Some(level) => level,
+                    None => return Err(format!("Unknown optimization level '{}'", flag))
+                };
+            },
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("Unknown option '{}'", flag));
+            },
+            value => positional.push(value)
+        }
+    }
+
+    let command = match positional.split_first() {
+        None => Command::Repl,
+        Some((&"repl", [])) => Command::Repl,
+        Some((&"dap", [])) => Command::Dap,
+        Some((&"lsp", [])) => Command::Lsp,
+        Some((&"run", [path])) => Command::Run(input(path)),
+        Some((&"compile", [path])) => Command::Compile { input: input(path), output },
+        Some((&"disasm", [path])) => Command::Disasm { input: input(path), format },
+        Some((&"debug", [path])) => Command::Debug(input(path)),
+        Some((&"check", paths)) if !paths.is_empty() => Command::Check(paths.iter().map(|p| input(p)).collect()),
+        Some((&"fmt", paths)) if !paths.is_empty() => Command::Fmt { inputs: paths.iter().map(|p| input(p)).collect(), check },
+        Some((&"repl", _)) | Some((&"run", _)) | Some((&"compile", _))
+            | Some((&"disasm", _)) | Some((&"check", _)) | Some((&"fmt", _))
+            | Some((&"debug", _)) | Some((&"dap", _)) | Some((&"lsp", _)) => {
+            return Err(format!("Wrong number of paths for '{}'", positional[0]));
+        },
+        Some((path, [])) => Command::Run(input(path)),
+        Some(_) => return Err("Too many arguments".to_string())
+    };
+
+    if let Some(flag) = flags.iter().find(|flag| !applies_to(flag, &command)) {
+        return Err(format!("Option '{}' does not apply to '{}'", flag, command.name()));
+    }
+
+    // Any of the trace filters imply tracing.
+    let trace = trace || trace_file.is_some() || trace_function.is_some() || trace_lines.is_some();
+    
+    Ok(Options {
+        command,
+        optimization_level,
+        trace,
+        trace_file,
+        trace_function,
+        trace_lines,
+        max_stack
+    })
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Run(_) => "run",
+            Command::Repl => "repl",
+            Command::Compile { .. } => "compile",
+            Command::Disasm { .. } => "disasm",
+            Command::Check(_) => "check",
+            Command::Debug(_) => "debug",
+            Command::Dap => "dap",
+            Command::Lsp => "lsp",
+            Command::Fmt { .. } => "fmt"
+        }
+    }
+}
+
+impl Display for Input {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Input::Stdin => write!(f, "<stdin>"),
+            Input::Path(path) => write!(f, "{}", path)
+        }
+    }
+}
+
+fn line_range(range: &str) -> Option<(usize, usize)> {
+    let (first, last) = match range.find('-') {
+        Some(split) => (&range[..split], &range[split + 1..]),
+        None => (range, range)
+    };
+    
+    match (first.parse(), last.parse()) {
+        (Ok(first), Ok(last)) if first <= last => Some((first, last)),
+        _ => None
+    }
+}
+
+// Whether a command uses the flag, since one it would silently ignore is
+// more likely a mistake than something the user meant.
+fn applies_to(flag: &str, command: &Command) -> bool {
+    let runs_program = matches!(command, Command::Run(_) | Command::Repl | Command::Debug(_));
+
+    match flag {
+        "--trace" | "--trace-file" | "--trace-function" | "--trace-lines" | "--max-stack" => runs_program,
+        "-o" => matches!(command, Command::Compile { .. }),
+        "--format" => matches!(command, Command::Disasm { .. }),
+        "--check" => matches!(command, Command::Fmt { .. }),
+        _ => !matches!(command, Command::Fmt { .. })
+    }
+}
+
+fn input(path: &str) -> Input {
+    if path == "-" {
+        Input::Stdin
+    } else {
+        Input::Path(path.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Options, String> {
+        let args: Vec<String> = line.split_whitespace().map(|arg| arg.to_string()).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn cli_defaults_to_repl() {
+        let options = parse_line("").unwrap();
+
+        assert_eq!(options.command, Command::Repl);
+        assert_eq!(options.optimization_level, OptimizationLevel::ConstantFolding);
+        assert_eq!(options.max_stack, DEFAULT_STACK_CAPACITY);
+        assert!(!options.trace);
+    }
+
+    #[test]
+    fn cli_runs_a_bare_path_or_stdin() {
+        assert_eq!(parse_line("script.lox").unwrap().command, Command::Run(Input::Path("script.lox".to_string())));
+        assert_eq!(parse_line("run -").unwrap().command, Command::Run(Input::Stdin));
+    }
+
+    #[test]
+    fn cli_accepts_flags_anywhere() {
+        let options = parse_line("--trace run -O2 a.lox --max-stack 16").unwrap();
+
+        assert_eq!(options.command, Command::Run(Input::Path("a.lox".to_string())));
+        assert_eq!(options.optimization_level, OptimizationLevel::Peephole);
+        assert_eq!(options.max_stack, 16);
+        assert!(options.trace);
+    }
+
+    #[test]
+    fn cli_parses_subcommand_options() {
+        assert_eq!(parse_line("disasm a.lox --format json").unwrap().command,
+            Command::Disasm { input: Input::Path("a.lox".to_string()), format: Format::Json });
+        assert_eq!(parse_line("compile - -o a.loxc").unwrap().command,
+            Command::Compile { input: Input::Stdin, output: Some("a.loxc".to_string()) });
+        assert_eq!(parse_line("fmt --check a.lox b.lox").unwrap().command,
+            Command::Fmt { inputs: vec![Input::Path("a.lox".to_string()), Input::Path("b.lox".to_string())], check: true });
+        assert_eq!(parse_line("dap -O2").unwrap().command, Command::Dap);
+        assert_eq!(parse_line("lsp").unwrap().command, Command::Lsp);
+    }
+
+    #[test]
+    fn cli_parses_trace_filters() {
+        let options = parse_line("a.lox --trace-function script --trace-lines 3-7 --trace-file t.jsonl").unwrap();
+
+        assert!(options.trace);
+        assert_eq!(options.trace_function, Some("script".to_string()));
+        assert_eq!(options.trace_lines, Some((3, 7)));
+        assert_eq!(options.trace_file, Some("t.jsonl".to_string()));
+        assert_eq!(parse_line("a.lox --trace-lines 4").unwrap().trace_lines, Some((4, 4)));
+        assert!(parse_line("a.lox --trace-lines 7-3").is_err());
+    }
+
+    #[test]
+    fn cli_rejects_bad_usage() {
+        assert!(parse_line("a.lox b.lox").is_err());
+        assert!(parse_line("run").is_err());
+        assert!(parse_line("check").is_err());
+        assert!(parse_line("dap a.lox").is_err());
+        assert!(parse_line("--max-stack").is_err());
+        assert!(parse_line("--max-stack 0 a.lox").is_err());
+        assert_eq!(parse_line("--max-stack 18446744073709551615 a.lox").err().unwrap(),
+            "Stack size '18446744073709551615' is larger than the maximum of 1048576");
+        assert!(parse_line("--max-stack 1048576 a.lox").is_ok());
+        assert!(parse_line("-O9 a.lox").is_err());
+        assert!(parse_line("--bogus a.lox").is_err());
+        assert!(parse_line("disasm a.lox --format xml").is_err());
+        assert!(parse_line("a.lox --gc-stress").is_err());
+    }
+
+    #[test]
+    fn cli_rejects_options_the_command_does_not_use() {
+        assert_eq!(parse_line("check a.lox --trace").err().unwrap(), "Option '--trace' does not apply to 'check'");
+        assert_eq!(parse_line("fmt -O2 a.lox").err().unwrap(), "Option '-O2' does not apply to 'fmt'");
+        assert!(parse_line("a.lox --format json").is_err());
+        assert!(parse_line("disasm a.lox -o out").is_err());
+        assert!(parse_line("run a.lox --check").is_err());
+        assert!(parse_line("dap --max-stack 16").is_err());
+        assert!(parse_line("--trace-lines 1-2").is_ok());
+        assert!(parse_line("debug a.lox --trace -O0").is_ok());
+    }
+}
@@ -0,0 +1,711 @@
+use std::fmt::{Display, Formatter, Result};
+use std::mem;
+use chunks::*;
+use optimizer;
+use runtime::LoxValue;
+use scanning::{Scanner, Token, TokenType};
+
+// Parsing recurses once per nested group or operator, so nesting is capped
+// well below what would overflow the native stack.
+const MAX_NESTING_DEPTH: usize = 256;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum OptimizationLevel {
+    None,
+    ConstantFolding,
+    Peephole
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompileError {
+    line_number: usize,
+    start: usize,
+    length: usize,
+    location: String,
+    message: &'static str
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary
+}
+
+struct PendingConstant {
+    offset: usize,
+    line_number: usize,
+    index: u8,
+    value: LoxValue
+}
+
+struct Compiler<'a> {
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    chunk: Chunk,
+    errors: Vec<CompileError>,
+    panic_mode: bool,
+    depth: usize,
+    optimization_level: OptimizationLevel,
+    // The constant loads at the very end of the chunk, in emission order.
+    // Anything else being emitted clears them, so they are always contiguous
+    // in both the code and the constant table and can be rewritten in place.
+    pending_constants: Vec<PendingConstant>
+}
+
+pub fn compile(source: &str, optimization_level: OptimizationLevel)
+    -> std::result::Result<Chunk, Vec<CompileError>> {
+    let mut scanner = Scanner::create(source);
+    let first = scanner.scan_token();
+
+    let mut compiler = Compiler {
+        scanner,
+        previous: first.clone(),
+        current: first,
+        chunk: Chunk::create("script"),
+        errors: Vec::new(),
+        panic_mode: false,
+        depth: 0,
+        optimization_level,
+        pending_constants: Vec::new()
+    };
+
+    compiler.report_current_if_error();
+    compiler.expression();
+    compiler.consume(TokenType::EndOfFile, "Expect end of expression");
+
+    // Parsing gives up at the first syntax error, but the rest of the
+    // source is still scanned for lexical errors.
+    while compiler.current.token_type() != TokenType::EndOfFile {
+        compiler.advance();
+    }
+    compiler.emit(Instruction::Return);
+
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+
+    if optimization_level >= OptimizationLevel::Peephole {
+        return Ok(optimizer::peephole(&compiler.chunk));
+    }
+
+    return Ok(compiler.chunk);
+}
+
+impl OptimizationLevel {
+    pub fn from_level(level: u8) -> Option<OptimizationLevel> {
+        match level {
+            0 => Some(OptimizationLevel::None),
+            1 => Some(OptimizationLevel::ConstantFolding),
+            2 => Some(OptimizationLevel::Peephole),
+            _ => None
+        }
+    }
+}
+
+impl CompileError {
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+
+    // The offending token as a byte offset into the source and a length
+    // in bytes.
+    pub fn span(&self) -> (usize, usize) {
+        (self.start, self.length)
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "[line {}] Error{}: {}", self.line_number, self.location, self.message)
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn advance(&mut self) {
+        let next = self.scanner.scan_token();
+        self.previous = mem::replace(&mut self.current, next);
+        self.report_current_if_error();
+    }
+
+    fn report_current_if_error(&mut self) {
+        // The scanner keeps producing tokens after an error, so skip past
+        // each one after reporting it. Doc comments are only for tools.
+        loop {
+            match self.current.token_type() {
+                TokenType::Error(message) => {
+                    // Lexical errors are reported even while recovering from
+                    // another error, so that one pass finds all of them.
+                    let error = Compiler::error_at(&self.current, message);
+                    self.errors.push(error);
+                    self.panic_mode = true;
+                },
+                TokenType::DocComment => {},
+                _ => return
+            }
+            self.current = self.scanner.scan_token();
+        }
+    }
+
+    fn consume(&mut self, expected: TokenType, message: &'static str) {
+        if self.current.token_type() == expected {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        if self.depth >= MAX_NESTING_DEPTH {
+            self.error_at_current("Expression nests too deeply");
+            return;
+        }
+
+        self.depth += 1;
+        self.parse_operand(precedence);
+        self.depth -= 1;
+    }
+
+    fn parse_operand(&mut self, precedence: Precedence) {
+        self.advance();
+
+        if !self.prefix(self.previous.token_type()) {
+            self.error_at_previous("Expect expression");
+            return;
+        }
+
+        while precedence <= Compiler::infix_precedence(self.current.token_type()) {
+            self.advance();
+            self.binary();
+        }
+    }
+
+    fn prefix(&mut self, token_type: TokenType) -> bool {
+        match token_type {
+            TokenType::LeftParen => self.grouping(),
+            TokenType::Minus | TokenType::Bang => self.unary(),
+            TokenType::Number => self.number(),
+            TokenType::True | TokenType::False | TokenType::Nil => self.literal(),
+            TokenType::String => self.string(),
+            TokenType::InterpolationStart => self.interpolation(),
+            _ => return false
+        };
+
+        return true;
+    }
+
+    fn infix_precedence(token_type: TokenType) -> Precedence {
+        match token_type {
+            TokenType::EqualEqual | TokenType::BangEqual => Precedence::Equality,
+            TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => Precedence::Comparison,
+            TokenType::Minus | TokenType::Plus => Precedence::Term,
+            TokenType::Slash | TokenType::Star => Precedence::Factor,
+            _ => Precedence::None
+        }
+    }
+
+    fn grouping(&mut self) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression");
+    }
+
+    fn number(&mut self) {
+        match Compiler::number_value(self.previous.lexeme()) {
+            Some(value) => self.emit_constant(LoxValue::number(value)),
+            None => self.error_at_previous("Invalid number literal")
+        }
+    }
+
+    fn literal(&mut self) {
+        let value = match self.previous.token_type() {
+            TokenType::True => LoxValue::boolean(true),
+            TokenType::False => LoxValue::boolean(false),
+            _ => LoxValue::nil()
+        };
+        self.emit_constant(value);
+    }
+
+    fn number_value(lexeme: &str) -> Option<f64> {
+        let digits: String = lexeme.chars().filter(|&c| c != '_').collect();
+
+        let radix = match digits.get(..2) {
+            Some("0x") | Some("0X") => 16,
+            Some("0b") | Some("0B") => 2,
+            _ => return digits.parse().ok()
+        };
+
+        // Accumulating in a float keeps literals past u64 range approximate
+        // rather than failing, as decimal literals do.
+        digits[2..].chars().try_fold(0.0, |value, c| c.to_digit(radix).map(|digit| value * radix as f64 + digit as f64))
+    }
+
+    fn string(&mut self) {
+        let value = LoxValue::string(self.previous.value().unwrap_or(""));
+        self.emit_constant(value);
+    }
+
+    // Lowers "a ${b} c" to "a" + stringified b + " c", leaving out empty
+    // fragments.
+    fn interpolation(&mut self) {
+        let mut started = self.string_fragment(false);
+
+        loop {
+            self.expression();
+            self.emit(Instruction::Stringify);
+            if started {
+                self.emit(Instruction::Add);
+            }
+            started = true;
+
+            match self.current.token_type() {
+                TokenType::InterpolationMiddle => {
+                    self.advance();
+                    self.string_fragment(true);
+                },
+                TokenType::InterpolationEnd => {
+                    self.advance();
+                    self.string_fragment(true);
+                    return;
+                },
+                _ => {
+                    self.error_at_current("Expect '}' after interpolated expression");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn string_fragment(&mut self, append: bool) -> bool {
+        let fragment = self.previous.value().unwrap_or("");
+        if fragment.is_empty() {
+            return false;
+        }
+
+        let value = LoxValue::string(fragment);
+        self.emit_constant(value);
+        if append {
+            self.emit(Instruction::Add);
+        }
+
+        return true;
+    }
+
+    fn unary(&mut self) {
+        let operator = self.previous.token_type();
+        let line_number = self.previous.line_number();
+
+        self.parse_precedence(Precedence::Unary);
+
+        let instruction = match operator {
+            TokenType::Minus => Instruction::Negate,
+            TokenType::Bang => Instruction::Not,
+            _ => return
+        };
+
+        self.emit_unary(line_number, instruction);
+    }
+
+    fn emit_unary(&mut self, line_number: usize, instruction: Instruction) {
+        if !self.fold_unary(instruction) {
+            self.emit_at(line_number, instruction);
+        }
+    }
+
+    fn binary(&mut self) {
+        let operator = self.previous.token_type();
+        let line_number = self.previous.line_number();
+        let precedence = Compiler::infix_precedence(operator);
+
+        self.parse_precedence(Compiler::next_precedence(precedence));
+
+        // The comparisons without an instruction of their own are the
+        // opposite comparison, negated.
+        let (instruction, negated) = match operator {
+            TokenType::Plus => (Instruction::Add, false),
+            TokenType::Minus => (Instruction::Subtract, false),
+            TokenType::Star => (Instruction::Multiply, false),
+            TokenType::Slash => (Instruction::Divide, false),
+            TokenType::EqualEqual => (Instruction::Equal, false),
+            TokenType::BangEqual => (Instruction::Equal, true),
+            TokenType::Greater => (Instruction::Greater, false),
+            TokenType::GreaterEqual => (Instruction::Less, true),
+            TokenType::Less => (Instruction::Less, false),
+            TokenType::LessEqual => (Instruction::Greater, true),
+            _ => return
+        };
+
+        if !self.fold_binary(instruction) {
+            self.emit_at(line_number, instruction);
+        }
+        if negated {
+            self.emit_unary(line_number, Instruction::Not);
+        }
+    }
+
+    fn next_precedence(precedence: Precedence) -> Precedence {
+        match precedence {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => Precedence::Primary
+        }
+    }
+
+    fn fold_unary(&mut self, instruction: Instruction) -> bool {
+        if self.optimization_level < OptimizationLevel::ConstantFolding {
+            return false;
+        }
+
+        let folded = match (self.pending_constants.last(), instruction) {
+            (Some(operand), Instruction::Negate) => operand.value.negate(),
+            (Some(operand), Instruction::Not) => Some(operand.value.not()),
+            _ => None
+        };
+
+        return self.replace_pending_constants(1, folded);
+    }
+
+    // Folding goes through the same LoxValue operations the virtual machine
+    // uses, so a folded result is bit for bit what execution would produce.
+    fn fold_binary(&mut self, instruction: Instruction) -> bool {
+        if self.optimization_level < OptimizationLevel::ConstantFolding {
+            return false;
+        }
+
+        let pending = self.pending_constants.len();
+        if pending < 2 {
+            return false;
+        }
+
+        let left = &self.pending_constants[pending - 2].value;
+        let right = &self.pending_constants[pending - 1].value;
+
+        let folded = match instruction {
+            Instruction::Add => left.add(right),
+            Instruction::Subtract => left.subtract(right),
+            Instruction::Multiply => left.multiply(right),
+            Instruction::Divide => left.divide(right),
+            Instruction::Equal => Some(left.equals(right)),
+            Instruction::Greater => left.greater(right),
+            Instruction::Less => left.less(right),
+            _ => None
+        };
+
+        return self.replace_pending_constants(2, folded);
+    }
+
+    fn replace_pending_constants(&mut self, count: usize, folded: Option<LoxValue>) -> bool {
+        let folded = match folded {
+            Some(value) => value,
+            None => return false
+        };
+
+        let first = self.pending_constants.len() - count;
+        let offset = self.pending_constants[first].offset;
+        let index = self.pending_constants[first].index;
+        let line_number = self.pending_constants[first].line_number;
+
+        self.pending_constants.truncate(first);
+        self.chunk.truncate(offset, index as usize);
+        self.emit_constant_at(line_number, folded);
+
+        return true;
+    }
+
+    fn emit_constant(&mut self, value: LoxValue) {
+        let line_number = self.previous.line_number();
+        self.emit_constant_at(line_number, value);
+    }
+
+    fn emit_constant_at(&mut self, line_number: usize, value: LoxValue) {
+        let index = match self.chunk.add_constant(value.clone()) {
+            Some(index) => index,
+            None => {
+                self.error_at_previous("Too many constants in one chunk");
+                return;
+            }
+        };
+
+        let offset = self.chunk.len();
+        self.write(line_number, Instruction::Constant(index));
+        self.pending_constants.push(PendingConstant { offset, line_number, index, value });
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let line_number = self.previous.line_number();
+        self.emit_at(line_number, instruction);
+    }
+
+    fn emit_at(&mut self, line_number: usize, instruction: Instruction) {
+        self.pending_constants.clear();
+        self.write(line_number, instruction);
+    }
+
+    fn write(&mut self, line_number: usize, instruction: Instruction) {
+        for byte in instruction.as_bytecode() {
+            self.chunk.write(line_number, byte);
+        }
+    }
+
+    fn error_at_current(&mut self, message: &'static str) {
+        let error = Compiler::error_at(&self.current, message);
+        self.report(error);
+    }
+
+    fn error_at_previous(&mut self, message: &'static str) {
+        let error = Compiler::error_at(&self.previous, message);
+        self.report(error);
+    }
+
+    fn error_at(token: &Token, message: &'static str) -> CompileError {
+        let location = match token.token_type() {
+            TokenType::EndOfFile => " at end".to_string(),
+            TokenType::Error("Unexpected character") => format!(" at '{}'", token.lexeme()),
+            TokenType::Error(_) => String::new(),
+            _ => format!(" at '{}'", token.lexeme())
+        };
+
+        CompileError {
+            line_number: token.line_number(),
+            start: token.lexeme_start(),
+            length: token.lexeme().len(),
+            location,
+            message
+        }
+    }
+
+    fn report(&mut self, error: CompileError) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.errors.push(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instructions(chunk: &Chunk) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        let mut bytecode = chunk.code();
+
+        while let (_, Some(instruction)) = Instruction::from_bytecode(&mut bytecode) {
+            instructions.push(instruction);
+        }
+
+        return instructions;
+    }
+
+    #[test]
+    fn compiler_emits_arithmetic_without_optimization() {
+        let chunk = compile("60 * 60 * 24", OptimizationLevel::None).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Multiply,
+            Instruction::Constant(2),
+            Instruction::Multiply,
+            Instruction::Return
+        ]);
+    }
+
+    #[test]
+    fn compiler_folds_constant_arithmetic() {
+        let chunk = compile("60 * 60 * 24", OptimizationLevel::ConstantFolding).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![Instruction::Constant(0), Instruction::Return]);
+        assert_eq!(chunk.constants_len(), 1);
+        assert_eq!(chunk.constant(0).unwrap().as_number(), Some(86400.0));
+    }
+
+    #[test]
+    fn compiler_folds_nested_and_unary_expressions() {
+        let chunk = compile("-(1 + 2) * -(3 - 5) / 4", OptimizationLevel::ConstantFolding).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![Instruction::Constant(0), Instruction::Return]);
+        assert_eq!(chunk.constant(0).unwrap().as_number(), Some(-1.5));
+    }
+
+    #[test]
+    fn compiler_folds_division_by_zero_to_infinity() {
+        let chunk = compile("1 / 0", OptimizationLevel::ConstantFolding).unwrap();
+        let negative = compile("-1 / 0", OptimizationLevel::ConstantFolding).unwrap();
+
+        assert_eq!(chunk.constant(0).unwrap().as_number(), Some(f64::INFINITY));
+        assert_eq!(negative.constant(0).unwrap().as_number(), Some(f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn compiler_emits_comparisons_as_equal_greater_less_and_not() {
+        let chunk = compile("1 <= 2 != !nil", OptimizationLevel::None).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Greater,
+            Instruction::Not,
+            Instruction::Constant(2),
+            Instruction::Not,
+            Instruction::Equal,
+            Instruction::Not,
+            Instruction::Return
+        ]);
+    }
+
+    #[test]
+    fn compiler_folds_comparisons_and_booleans() {
+        let cases = [
+            ("1 < 2", Some(true)),
+            ("2 <= 1", Some(false)),
+            ("3 >= 3 == !false", Some(true)),
+            ("\"a\" + \"b\" == \"ab\"", Some(true)),
+            ("nil != false", Some(true)),
+            ("0 / 0 == 0 / 0", Some(false)),
+            ("!nil", Some(true)),
+            ("!0", Some(false))
+        ];
+
+        for &(source, expected) in cases.iter() {
+            let chunk = compile(source, OptimizationLevel::ConstantFolding).unwrap();
+
+            assert_eq!(instructions(&chunk), vec![Instruction::Constant(0), Instruction::Return], "{}", source);
+            assert_eq!(chunk.constant(0).unwrap().as_boolean(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn compiler_leaves_comparisons_that_would_fail_to_runtime() {
+        let chunk = compile("1 < \"a\"", OptimizationLevel::ConstantFolding).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Less,
+            Instruction::Return
+        ]);
+    }
+
+    #[test]
+    fn compiler_lowers_string_interpolation() {
+        let chunk = compile("\"a ${1 + 2} b ${\"c\"}\"", OptimizationLevel::ConstantFolding).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Stringify,
+            Instruction::Add,
+            Instruction::Constant(2),
+            Instruction::Add,
+            Instruction::Constant(3),
+            Instruction::Stringify,
+            Instruction::Add,
+            Instruction::Return
+        ]);
+        assert_eq!(chunk.constant(0).unwrap().as_string(), Some("a "));
+        assert_eq!(chunk.constant(1).unwrap().as_number(), Some(3.0));
+        assert_eq!(chunk.constant(2).unwrap().as_string(), Some(" b "));
+        assert_eq!(chunk.constant(3).unwrap().as_string(), Some("c"));
+    }
+
+    #[test]
+    fn compiler_reports_unclosed_interpolation() {
+        let errors = compile("\"a ${1 + 2", OptimizationLevel::None).err().unwrap();
+
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect '}' after interpolated expression");
+    }
+
+    #[test]
+    fn compiler_reads_extended_number_literals() {
+        let cases = [("0xff", 255.0), ("0XdEaD_BEEF", 3735928559.0), ("0b1010_0101", 165.0),
+            ("1_000_000", 1000000.0), ("2.5e3", 2500.0), ("1e-9", 1e-9), ("6.02E+2_3", 6.02e23)];
+
+        for &(source, expected) in &cases {
+            let chunk = compile(source, OptimizationLevel::None).unwrap();
+            assert_eq!(chunk.constant(0).unwrap().as_number(), Some(expected), "{}", source);
+        }
+    }
+
+    #[test]
+    fn compiler_skips_comments_and_counts_their_lines() {
+        let chunk = compile("/// doc\n1 /* a\n/* nested */\n*/ +\n// line\n2", OptimizationLevel::None).unwrap();
+
+        assert_eq!(instructions(&chunk), vec![
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Add,
+            Instruction::Return
+        ]);
+        assert_eq!((chunk.line(0), chunk.line(2), chunk.line(4)), (Some(2), Some(6), Some(4)));
+    }
+
+    #[test]
+    fn compiler_reports_missing_operand() {
+        let errors = compile("1 +", OptimizationLevel::None).err().unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect expression");
+    }
+
+    #[test]
+    fn compiler_reports_every_lexical_error() {
+        let errors = compile("1 + @@ * (2 ~\n- \"open", OptimizationLevel::None).err().unwrap();
+
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(messages, vec![
+            "[line 1] Error at '@@': Unexpected character",
+            "[line 1] Error at '~': Unexpected character",
+            "[line 2] Error: Unterminated string"
+        ]);
+        assert_eq!(errors[1].span(), (12, 1));
+    }
+
+    #[test]
+    fn compiler_reports_unclosed_grouping() {
+        let errors = compile("(1 + 2", OptimizationLevel::None).err().unwrap();
+
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect ')' after expression");
+    }
+
+    #[test]
+    fn compiler_reports_deep_nesting_instead_of_overflowing() {
+        for &(open, close) in [("(", ")"), ("-", ""), ("\"${", "}\"")].iter() {
+            let source = format!("{}1{}", open.repeat(100_000), close.repeat(100_000));
+
+            let errors = compile(&source, OptimizationLevel::None).err().unwrap();
+
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].message(), "Expression nests too deeply");
+        }
+
+        let source = format!("{}1{}", "(".repeat(255), ")".repeat(255));
+        assert!(compile(&source, OptimizationLevel::ConstantFolding).is_ok());
+    }
+}
@@ -0,0 +1,442 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use serde_json::Value;
+use chunks::Chunk;
+use compiler;
+use compiler::OptimizationLevel;
+use debugger;
+use debugger::{Breakpoints, DebugFrontEnd, Debugger, Paused, Resume};
+use protocol::{read_message, write_message};
+use runtime::{ExecutionResult, VirtualMachine};
+
+const THREAD_ID: i64 = 1;
+const LOCALS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+
+struct Connection {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    seq: i64,
+    disconnected: bool
+}
+
+struct Program {
+    path: String,
+    chunk: Chunk,
+    stop_on_entry: bool
+}
+
+// Answers requests while the machine is paused inside a run.
+struct AdapterFrontEnd {
+    connection: Rc<RefCell<Connection>>,
+    path: String,
+    stop_on_entry: bool,
+    entered: bool
+}
+
+// Serves one debugging session over the Debug Adapter Protocol, returning
+// once the client disconnects or closes the input.
+pub fn serve(input: Box<dyn BufRead>, output: Box<dyn Write>, optimization_level: OptimizationLevel) {
+    let connection = Rc::new(RefCell::new(Connection {
+        input,
+        output,
+        seq: 0,
+        disconnected: false
+    }));
+
+    let mut program: Option<Program> = None;
+    let mut requested_breakpoints: Vec<i64> = Vec::new();
+
+    loop {
+        let request = match connection.borrow_mut().read() {
+            Some(request) => request,
+            None => return
+        };
+        let arguments = request["arguments"].clone();
+
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                let mut connection = connection.borrow_mut();
+                connection.respond(&request, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true
+                }));
+                connection.event("initialized", json!({}));
+            },
+            "launch" => match launch(&arguments, optimization_level) {
+                Ok(launched) => {
+                    connection.borrow_mut().respond(&request, json!({}));
+                    program = Some(launched);
+                },
+                Err(message) => connection.borrow_mut().fail(&request, &message)
+            },
+            "setBreakpoints" => {
+                requested_breakpoints = breakpoint_lines(&arguments);
+                let lines: Vec<Value> = requested_breakpoints.iter()
+                    .map(|&line| match program {
+                        Some(ref program) => resolved_breakpoint(&program.chunk, line),
+                        None => json!({ "verified": false, "line": line })
+                    })
+                    .collect();
+                connection.borrow_mut().respond(&request, json!({ "breakpoints": lines }));
+            },
+            "setExceptionBreakpoints" => connection.borrow_mut().respond(&request, json!({})),
+            "threads" => connection.borrow_mut().respond(&request, threads()),
+            "configurationDone" => {
+                connection.borrow_mut().respond(&request, json!({}));
+
+                if let Some(ref program) = program {
+                    run(&connection, program, &requested_breakpoints);
+                }
+
+                if connection.borrow().disconnected {
+                    return;
+                }
+            },
+            "disconnect" => {
+                connection.borrow_mut().respond(&request, json!({}));
+                return;
+            },
+            command => connection.borrow_mut().fail(&request, &format!("Unsupported request '{}'", command))
+        }
+    }
+}
+
+fn launch(arguments: &Value, optimization_level: OptimizationLevel) -> Result<Program, String> {
+    let path = arguments["program"].as_str().ok_or("Launch needs a program to debug")?;
+    let source = fs::read_to_string(path).map_err(|error| format!("Could not read {}: {}", path, error))?;
+
+    let chunk = compiler::compile(&source, optimization_level).map_err(|errors| {
+        errors.iter().map(|error| error.to_string()).collect::<Vec<String>>().join("\n")
+    })?;
+
+    Ok(Program {
+        path: path.to_string(),
+        chunk,
+        stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false)
+    })
+}
+
+fn run(connection: &Rc<RefCell<Connection>>, program: &Program, breakpoint_lines: &[i64]) {
+    let mut debugger = Debugger::create(Box::new(AdapterFrontEnd {
+        connection: connection.clone(),
+        path: program.path.clone(),
+        stop_on_entry: program.stop_on_entry,
+        entered: false
+    }));
+
+    for &line in breakpoint_lines {
+        if line > 0 {
+            debugger.breakpoints().add(&program.chunk, line as usize);
+        }
+    }
+
+    let mut vm = VirtualMachine::create();
+    vm.set_debugger(debugger);
+    let result = vm.run(&program.chunk);
+
+    let mut connection = connection.borrow_mut();
+    if connection.disconnected {
+        return;
+    }
+
+    let exit_code = match result {
+        ExecutionResult::Ok => {
+            if let Some(value) = vm.returned_value() {
+                connection.event("output", json!({ "category": "stdout", "output": format!("{:?}\n", value) }));
+            }
+            0
+        },
+        ExecutionResult::Stopped => 0,
+        ExecutionResult::RuntimeError(message) | ExecutionResult::StaticError(message) => {
+            connection.event("output", json!({ "category": "stderr", "output": format!("{}\n", message) }));
+            70
+        }
+    };
+
+    connection.event("exited", json!({ "exitCode": exit_code }));
+    connection.event("terminated", json!({}));
+}
+
+impl DebugFrontEnd for AdapterFrontEnd {
+    fn paused(&mut self, breakpoints: &mut Breakpoints, state: &Paused) -> Resume {
+        let at_breakpoint = breakpoints.contains(state.line);
+
+        let reason = if !self.entered {
+            self.entered = true;
+            if !self.stop_on_entry && !at_breakpoint {
+                return Resume::Continue;
+            }
+            if at_breakpoint { "breakpoint" } else { "entry" }
+        } else if at_breakpoint {
+            "breakpoint"
+        } else {
+            "step"
+        };
+
+        let mut connection = self.connection.borrow_mut();
+        connection.event("stopped", json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true
+        }));
+
+        loop {
+            let request = match connection.read() {
+                Some(request) => request,
+                None => return Resume::Stop
+            };
+            let arguments = &request["arguments"];
+
+            let resume = match request["command"].as_str().unwrap_or("") {
+                "continue" => Some(Resume::Continue),
+                "next" => Some(Resume::StepOver),
+                "stepIn" => Some(Resume::StepInto),
+                "stepOut" => Some(Resume::StepOut),
+                "disconnect" => {
+                    connection.disconnected = true;
+                    Some(Resume::Stop)
+                },
+                _ => None
+            };
+
+            if let Some(resume) = resume {
+                connection.respond(&request, json!({ "allThreadsContinued": true }));
+                return resume;
+            }
+
+            match request["command"].as_str().unwrap_or("") {
+                "threads" => connection.respond(&request, threads()),
+                "stackTrace" => {
+                    let frames: Vec<Value> = state.frames.iter().rev().enumerate()
+                        .map(|(id, frame)| json!({
+                            "id": id,
+                            "name": frame.function,
+                            "line": frame.line,
+                            "column": 1,
+                            "source": { "path": self.path }
+                        }))
+                        .collect();
+                    let total = frames.len();
+                    connection.respond(&request, json!({ "stackFrames": frames, "totalFrames": total }));
+                },
+                "scopes" => connection.respond(&request, json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": LOCALS_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false }
+                ]})),
+                "variables" => {
+                    let variables: Vec<Value> = match arguments["variablesReference"].as_i64() {
+                        Some(LOCALS_REFERENCE) => state.chunk.locals_at(state.ip).iter()
+                            .map(|local| json!({
+                                "name": local.name,
+                                "value": state.stack.get(local.slot).map(|v| format!("{:?}", v)).unwrap_or_default(),
+                                "variablesReference": 0
+                            }))
+                            .collect(),
+                        Some(STACK_REFERENCE) => state.stack.iter().enumerate()
+                            .map(|(slot, value)| json!({
+                                "name": format!("sp[{}]", slot),
+                                "value": format!("{:?}", value),
+                                "variablesReference": 0
+                            }))
+                            .collect(),
+                        _ => Vec::new()
+                    };
+                    connection.respond(&request, json!({ "variables": variables }));
+                },
+                "setBreakpoints" => {
+                    breakpoints.clear();
+                    let lines: Vec<Value> = breakpoint_lines(arguments).into_iter()
+                        .map(|line| {
+                            if line > 0 {
+                                breakpoints.add(state.chunk, line as usize);
+                            }
+                            resolved_breakpoint(state.chunk, line)
+                        })
+                        .collect();
+                    connection.respond(&request, json!({ "breakpoints": lines }));
+                },
+                "evaluate" => {
+                    let expression = arguments["expression"].as_str().unwrap_or("");
+                    connection.respond(&request, json!({
+                        "result": debugger::evaluate(expression),
+                        "variablesReference": 0
+                    }));
+                },
+                command => connection.fail(&request, &format!("Unsupported request '{}'", command))
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn read(&mut self) -> Option<Value> {
+        loop {
+            match read_message(&mut *self.input) {
+                Ok(Some(Ok(message))) => return Some(message),
+                // Without a request there is nothing to fail, so the client
+                // hears about it on its debug console.
+                Ok(Some(Err(error))) => self.event("output", json!({
+                    "category": "console",
+                    "output": format!("Ignored a message that is not JSON: {}\n", error)
+                })),
+                // A broken or closed connection ends the session either way.
+                Ok(None) | Err(_) => return None
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let _ = write_message(&mut *self.output, &message);
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body
+        }));
+    }
+}
+
+fn threads() -> Value {
+    json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })
+}
+
+fn breakpoint_lines(arguments: &Value) -> Vec<i64> {
+    match arguments["breakpoints"].as_array() {
+        Some(breakpoints) => breakpoints.iter().filter_map(|b| b["line"].as_i64()).collect(),
+        None => Vec::new()
+    }
+}
+
+fn resolved_breakpoint(chunk: &Chunk, line: i64) -> Value {
+    let resolved = if line > 0 { chunk.nearest_line_with_code(line as usize) } else { None };
+
+    match resolved {
+        Some(resolved) => json!({ "verified": true, "line": resolved }),
+        None => json!({ "verified": false, "line": line })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use protocol::{dap_request as request, exchange, frame};
+
+    fn session(name: &str, source: &str, stop_on_entry: bool, requests: Vec<Value>) -> Vec<Value> {
+        let path = env::temp_dir().join(format!("rlox-dap-{}-{}.lox", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+
+        let mut script = vec![
+            request(1, "initialize", json!({ "adapterID": "rlox" })),
+            request(2, "launch", json!({ "program": path.to_str().unwrap(), "stopOnEntry": stop_on_entry }))
+        ];
+        script.extend(requests);
+
+        let (_, messages) = exchange(frame(&script), |input, output| serve(input, output, OptimizationLevel::None));
+        fs::remove_file(&path).unwrap();
+
+        return messages;
+    }
+
+    fn describe(message: &Value) -> String {
+        match message["type"].as_str() {
+            Some("event") => format!("event {}", message["event"].as_str().unwrap()),
+            _ => format!("{} {}", message["command"].as_str().unwrap(), message["success"])
+        }
+    }
+
+    #[test]
+    fn adapter_stops_at_breakpoints_and_inspects_state() {
+        let messages = session("breakpoints", "1 +\n\n2 *\n\n3", false, vec![
+            request(3, "setBreakpoints", json!({ "source": {}, "breakpoints": [{ "line": 4 }] })),
+            request(4, "configurationDone", json!({})),
+            request(5, "stackTrace", json!({ "threadId": 1 })),
+            request(6, "variables", json!({ "variablesReference": 2 })),
+            request(7, "evaluate", json!({ "expression": "6 / 4" })),
+            request(8, "next", json!({ "threadId": 1 })),
+            request(9, "continue", json!({ "threadId": 1 })),
+            request(10, "continue", json!({ "threadId": 1 })),
+            request(11, "disconnect", json!({}))
+        ]);
+
+        let flow: Vec<String> = messages.iter().map(describe).collect();
+        assert_eq!(flow, vec![
+            "initialize true", "event initialized", "launch true", "setBreakpoints true",
+            "configurationDone true", "event stopped", "stackTrace true", "variables true",
+            "evaluate true", "next true", "event stopped", "continue true", "event stopped",
+            "continue true", "event output", "event exited", "event terminated", "disconnect true"
+        ]);
+        assert_eq!(messages[3]["body"]["breakpoints"][0], json!({ "verified": true, "line": 5 }));
+        assert_eq!(messages[5]["body"]["reason"], "breakpoint");
+        assert_eq!(messages[6]["body"]["stackFrames"][0]["line"], 5);
+        assert_eq!(messages[7]["body"]["variables"][1], json!({ "name": "sp[1]", "value": "2", "variablesReference": 0 }));
+        assert_eq!(messages[8]["body"]["result"], "1.5");
+        assert_eq!(messages[10]["body"]["reason"], "step");
+        assert_eq!(messages[14]["body"]["output"], "7\n");
+        assert_eq!(messages[15]["body"]["exitCode"], 0);
+    }
+
+    #[test]
+    fn adapter_disconnects_while_paused() {
+        let messages = session("disconnect", "1 + 2", true, vec![
+            request(3, "configurationDone", json!({})),
+            request(4, "disconnect", json!({})),
+            request(5, "threads", json!({}))
+        ]);
+
+        let flow: Vec<String> = messages.iter().map(describe).collect();
+        assert_eq!(flow, vec![
+            "initialize true", "event initialized", "launch true", "configurationDone true",
+            "event stopped", "disconnect true"
+        ]);
+        assert_eq!(messages[4]["body"]["reason"], "entry");
+    }
+
+    #[test]
+    fn adapter_reports_compile_errors_on_launch() {
+        let messages = session("errors", "1 +", false, vec![request(3, "disconnect", json!({}))]);
+
+        assert_eq!(describe(&messages[2]), "launch false");
+        assert_eq!(messages[2]["message"], "[line 1] Error at end: Expect expression");
+    }
+
+    #[test]
+    fn adapter_reports_bodies_that_are_not_json_and_carries_on() {
+        let mut input = frame(&[request(1, "initialize", json!({ "adapterID": "rlox" }))]);
+        input.extend(b"Content-Length: 3\r\n\r\n{]}");
+        input.extend(frame(&[request(2, "threads", json!({}))]));
+
+        let (_, messages) = exchange(input, |input, output| serve(input, output, OptimizationLevel::None));
+
+        let flow: Vec<String> = messages.iter().map(describe).collect();
+        assert_eq!(flow, vec!["initialize true", "event initialized", "event output", "threads true"]);
+        assert!(messages[2]["body"]["output"].as_str().unwrap().starts_with("Ignored a message that is not JSON"));
+    }
+}
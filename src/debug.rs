@@ -1,29 +1,267 @@
+use std::io;
+use std::io::Write;
 use chunks::*;
+use serde_json;
+use serde_json::Value;
+use runtime::LoxValue;
 
-pub fn dissassemble_chunk(chunk: &Chunk) {
-    println!("=== {} ===", chunk.name());
-    
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json
+}
+
+pub struct Disassembly {
+    name: String,
+    instructions: Vec<DisassembledInstruction>
+}
+
+pub struct DisassembledInstruction {
+    offset: usize,
+    line: usize,
+    instruction: Instruction,
+    constant: Option<LoxValue>
+}
+
+pub fn disassemble(chunk: &Chunk) -> Disassembly {
+    let mut instructions = Vec::new();
     let mut bytecode = chunk.code();
-    
+
     let mut offset = 0;
     while let (bytes_consumed, Some(instruction)) = Instruction::from_bytecode(&mut bytecode) {
-        print!("{:04x?}\t{:>4}\t", offset, chunk.line(offset));
-        disassemble_instruction(chunk, &instruction);
-        offset = offset + bytes_consumed;
+        instructions.push(DisassembledInstruction {
+            offset,
+            line: chunk.line(offset).unwrap_or(0),
+            instruction,
+            constant: instruction.constant_index().and_then(|index| chunk.constant(index)).cloned()
+        });
+        offset += bytes_consumed;
     }
 
-    println!("=== {} ===", chunk.name());
-    println!();
+    Disassembly {
+        name: chunk.name().to_owned(),
+        instructions
+    }
+}
+
+pub fn write_chunk(chunk: &Chunk, format: Format, out: &mut dyn Write) -> io::Result<()> {
+    let disassembly = disassemble(chunk);
+
+    match format {
+        Format::Text => disassembly.write_text(out),
+        Format::Json => disassembly.write_json(out)
+    }
+}
+
+pub fn dissassemble_chunk(chunk: &Chunk) {
+    let stdout = io::stdout();
+    write_chunk(chunk, Format::Text, &mut stdout.lock())
+        .expect("There was a problem writing to console");
 }
 
 pub fn disassemble_instruction(chunk: &Chunk, instruction: &Instruction) {
+    let constant = instruction.constant_index().and_then(|index| chunk.constant(index));
+    println!("{}", describe(instruction, constant));
+}
+
+impl Disassembly {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn instructions(&self) -> &[DisassembledInstruction] {
+        self.instructions.as_slice()
+    }
+
+    pub fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "=== {} ===", self.name)?;
+
+        for instruction in &self.instructions {
+            writeln!(out, "{:04x?}\t{:>4}\t{}",
+                instruction.offset,
+                instruction.line,
+                describe(&instruction.instruction, instruction.constant.as_ref()))?;
+        }
+
+        writeln!(out, "=== {} ===", self.name)?;
+        writeln!(out)
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        let instructions: Vec<Value> = self.instructions.iter()
+            .map(|instruction| {
+                let mut object = json!({
+                    "offset": instruction.offset,
+                    "line": instruction.line,
+                    "opcode": instruction.opcode(),
+                    "operands": instruction.operands()
+                });
+                if let Some(ref constant) = instruction.constant {
+                    object["constant"] = json_value(constant);
+                }
+                object
+            })
+            .collect();
+
+        serde_json::to_writer(&mut *out, &json!({ "name": self.name, "instructions": instructions }))?;
+        writeln!(out)
+    }
+}
+
+impl DisassembledInstruction {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn opcode(&self) -> &'static str {
+        opcode_name(&self.instruction)
+    }
+
+    pub fn operands(&self) -> Vec<u8> {
+        let mut bytecode = self.instruction.as_bytecode();
+        bytecode.remove(0);
+        return bytecode;
+    }
+
+    pub fn constant(&self) -> Option<&LoxValue> {
+        self.constant.as_ref()
+    }
+}
+
+pub fn opcode_name(instruction: &Instruction) -> &'static str {
     match instruction {
-        Instruction::Return => println!("RET"),
-        Instruction::Constant(index) => println!("CONST  c[{:02x?}] '{:?}'", index, chunk.constant(*index)),
-        Instruction::Negate => println!("NEG    sp[-1]"),
-        Instruction::Add => println!("ADD   sp[-1]  sp[-2]"),
-        Instruction::Subtract => println!("SUB  sp[-2]  sp[-1]"),
-        Instruction::Multiply => println!("MULT sp[-2]  sp[-1]"),
-        Instruction::Divide => println!("DIV    sp[-2]  sp[-1]")
+        Instruction::Return => "RET",
+        Instruction::Constant(_) => "CONST",
+        Instruction::Negate => "NEG",
+        Instruction::Add => "ADD",
+        Instruction::Subtract => "SUB",
+        Instruction::Multiply => "MULT",
+        Instruction::Divide => "DIV",
+        Instruction::AddConstant(_) => "ADDC",
+        Instruction::SubtractConstant(_) => "SUBC",
+        Instruction::MultiplyConstant(_) => "MULTC",
+        Instruction::DivideConstant(_) => "DIVC",
+        Instruction::Stringify => "TOSTR",
+        Instruction::Not => "NOT",
+        Instruction::Equal => "EQ",
+        Instruction::Greater => "GT",
+        Instruction::Less => "LT"
+    }
+}
+
+fn describe(instruction: &Instruction, constant: Option<&LoxValue>) -> String {
+    // An index past the constants means a corrupt chunk, which shouldn't
+    // pass for a nil.
+    let constant = match (constant, instruction.constant_index()) {
+        (Some(constant), _) => format!("'{:?}'", constant),
+        (None, Some(index)) => format!("<missing constant {}>", index),
+        (None, None) => String::new()
+    };
+
+    match instruction {
+        Instruction::Return => "RET".to_string(),
+        Instruction::Constant(index) => format!("CONST  c[{:02x?}] {}", index, constant),
+        Instruction::Negate => "NEG    sp[-1]".to_string(),
+        Instruction::Add => "ADD   sp[-1]  sp[-2]".to_string(),
+        Instruction::Subtract => "SUB  sp[-2]  sp[-1]".to_string(),
+        Instruction::Multiply => "MULT sp[-2]  sp[-1]".to_string(),
+        Instruction::Divide => "DIV    sp[-2]  sp[-1]".to_string(),
+        Instruction::AddConstant(index) => format!("ADDC   sp[-1]  c[{:02x?}] {}", index, constant),
+        Instruction::SubtractConstant(index) => format!("SUBC   sp[-1]  c[{:02x?}] {}", index, constant),
+        Instruction::MultiplyConstant(index) => format!("MULTC  sp[-1]  c[{:02x?}] {}", index, constant),
+        Instruction::DivideConstant(index) => format!("DIVC   sp[-1]  c[{:02x?}] {}", index, constant),
+        Instruction::Stringify => "TOSTR  sp[-1]".to_string(),
+        Instruction::Not => "NOT    sp[-1]".to_string(),
+        Instruction::Equal => "EQ     sp[-2]  sp[-1]".to_string(),
+        Instruction::Greater => "GT     sp[-2]  sp[-1]".to_string(),
+        Instruction::Less => "LT     sp[-2]  sp[-1]".to_string()
+    }
+}
+
+// Values are tagged with their type, so that a string constant reading
+// "NaN" stays apart from the number, which JSON can only write as a string.
+pub fn json_value(value: &LoxValue) -> Value {
+    if let Some(number) = value.as_number() {
+        if number.is_finite() {
+            json!({ "type": "number", "value": number })
+        } else {
+            json!({ "type": "number", "value": format!("{:?}", value) })
+        }
+    } else if let Some(boolean) = value.as_boolean() {
+        json!({ "type": "boolean", "value": boolean })
+    } else if let Some(text) = value.as_string() {
+        json!({ "type": "string", "value": text })
+    } else {
+        json!({ "type": "nil" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_chunk() -> Chunk {
+        Chunk::assemble(&[LoxValue::number(1.5)], &[(1, Instruction::Constant(0)), (1, Instruction::Negate), (2, Instruction::Return)])
+    }
+
+    fn render(chunk: &Chunk, format: Format) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        write_chunk(chunk, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn disassembler_renders_text_listing() {
+        assert_eq!(render(&sample_chunk(), Format::Text),
+            "=== test ===\n\
+             0000\t   1\tCONST  c[00] '1.5'\n\
+             0002\t   1\tNEG    sp[-1]\n\
+             0003\t   2\tRET\n\
+             === test ===\n\n");
+    }
+
+    #[test]
+    fn disassembler_renders_json() {
+        assert_eq!(render(&sample_chunk(), Format::Json),
+            "{\"name\":\"test\",\"instructions\":[\
+             {\"offset\":0,\"line\":1,\"opcode\":\"CONST\",\"operands\":[0],\"constant\":{\"type\":\"number\",\"value\":1.5}},\
+             {\"offset\":2,\"line\":1,\"opcode\":\"NEG\",\"operands\":[]},\
+             {\"offset\":3,\"line\":2,\"opcode\":\"RET\",\"operands\":[]}]}\n");
+    }
+
+    #[test]
+    fn disassembler_lists_constants_a_chunk_does_not_have() {
+        let chunk = Chunk::assemble(&[], &[(1, Instruction::Constant(3))]);
+
+        assert_eq!(render(&chunk, Format::Json),
+            "{\"name\":\"test\",\"instructions\":[{\"offset\":0,\"line\":1,\"opcode\":\"CONST\",\"operands\":[3]}]}\n");
+        assert!(render(&chunk, Format::Text).contains("CONST  c[03] <missing constant 3>\n"));
+    }
+
+    #[test]
+    fn disassembler_tells_numbers_from_strings() {
+        let chunk = Chunk::assemble(&[LoxValue::number(f64::NAN), LoxValue::string("NaN"), LoxValue::nil()],
+            &[(1, Instruction::Constant(0)), (1, Instruction::Constant(1)), (1, Instruction::Constant(2))]);
+
+        let constants: Vec<Value> = disassemble(&chunk).instructions().iter()
+            .map(|instruction| json_value(instruction.constant().unwrap()))
+            .collect();
+        assert_eq!(constants, vec![
+            json!({ "type": "number", "value": "NaN" }),
+            json!({ "type": "string", "value": "NaN" }),
+            json!({ "type": "nil" })
+        ]);
+    }
+
+    #[test]
+    fn disassembler_escapes_json_strings() {
+        let mut chunk = Chunk::create("a \"quoted\"\\\n\u{1}");
+        chunk.write(1, Instruction::Return.as_bytecode()[0]);
+
+        assert!(render(&chunk, Format::Json).starts_with("{\"name\":\"a \\\"quoted\\\"\\\\\\n\\u0001\","));
     }
 }
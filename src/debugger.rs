@@ -0,0 +1,384 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use chunks::{Chunk, Instruction};
+use compiler;
+use compiler::OptimizationLevel;
+use debug::opcode_name;
+use runtime::{ExecutionResult, LoxValue, VirtualMachine};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Resume {
+    Continue,
+    StepInto,
+    StepOver,
+    StepOut,
+    Stop
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StackFrame {
+    pub function: String,
+    pub ip: usize,
+    pub line: usize
+}
+
+// Everything a front end can see while the machine is paused before
+// executing the instruction at ip. Frames are innermost last.
+pub struct Paused<'a> {
+    pub chunk: &'a Chunk,
+    pub ip: usize,
+    pub line: usize,
+    pub instruction: &'a Instruction,
+    pub stack: &'a [LoxValue],
+    pub frames: &'a [StackFrame]
+}
+
+pub trait DebugFrontEnd {
+    fn paused(&mut self, breakpoints: &mut Breakpoints, state: &Paused) -> Resume;
+}
+
+#[derive(Default)]
+pub struct Breakpoints {
+    lines: BTreeSet<usize>
+}
+
+#[derive(Clone, Copy)]
+enum Stepping {
+    Entry,
+    Run,
+    Into { line: usize, depth: usize },
+    Over { line: usize, depth: usize },
+    Out { depth: usize }
+}
+
+pub struct Debugger {
+    breakpoints: Breakpoints,
+    stepping: Stepping,
+    previous: Option<(usize, usize)>,
+    front_end: Box<dyn DebugFrontEnd>
+}
+
+impl Breakpoints {
+    // Breakpoints on lines without code move down to the next line which has
+    // some, the same as most debuggers. Returns the line actually used.
+    pub fn add(&mut self, chunk: &Chunk, line: usize) -> Option<usize> {
+        let resolved = chunk.nearest_line_with_code(line)?;
+        self.lines.insert(resolved);
+        return Some(resolved);
+    }
+
+    pub fn remove(&mut self, line: usize) -> bool {
+        self.lines.remove(&line)
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        self.lines.contains(&line)
+    }
+
+    pub fn lines(&self) -> Vec<usize> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
+impl Debugger {
+    // The debugger pauses before the first instruction, so breakpoints can
+    // be set once the front end has seen the chunk.
+    pub fn create(front_end: Box<dyn DebugFrontEnd>) -> Debugger {
+        Debugger {
+            breakpoints: Breakpoints::default(),
+            stepping: Stepping::Entry,
+            previous: None,
+            front_end
+        }
+    }
+
+    pub fn breakpoints(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    // Called by the machine before every instruction. Returns false when the
+    // front end asked to stop the program.
+    pub fn before_instruction(&mut self, state: &Paused) -> bool {
+        let depth = state.frames.len();
+        let position = (state.line, depth);
+        let new_line = self.previous != Some(position);
+        self.previous = Some(position);
+
+        let pause = match self.stepping {
+            Stepping::Entry => true,
+            Stepping::Run => false,
+            Stepping::Into { line, depth: from } => line != state.line || from != depth,
+            Stepping::Over { line, depth: from } => depth < from || (depth == from && line != state.line),
+            Stepping::Out { depth: from } => depth < from
+        };
+
+        let at_breakpoint = new_line && self.breakpoints.contains(state.line);
+        if !pause && !at_breakpoint {
+            return true;
+        }
+
+        self.stepping = match self.front_end.paused(&mut self.breakpoints, state) {
+            Resume::Continue => Stepping::Run,
+            Resume::StepInto => Stepping::Into { line: state.line, depth },
+            Resume::StepOver => Stepping::Over { line: state.line, depth },
+            Resume::StepOut => Stepping::Out { depth },
+            Resume::Stop => return false
+        };
+
+        return true;
+    }
+}
+
+const HELP: &str = "\
+break <line>     set a breakpoint, or list them without a line
+delete <line>    remove a breakpoint, or all of them without a line
+continue, c      run until the next breakpoint
+step, s          run to the next line, entering calls
+next, n          run to the next line, stepping over calls
+finish           run until the current function returns
+backtrace, bt    print the call stack
+locals           print the local variables of the current frame
+stack            print the value stack
+print <expr>     evaluate an expression
+quit             stop the program";
+
+// A line oriented front end for driving the debugger from a terminal.
+pub struct ConsoleFrontEnd<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    source: Vec<String>
+}
+
+impl<R: BufRead, W: Write> ConsoleFrontEnd<R, W> {
+    pub fn create(input: R, output: W, source: Option<&str>) -> ConsoleFrontEnd<R, W> {
+        ConsoleFrontEnd {
+            input,
+            output,
+            source: source.map(|s| s.lines().map(|l| l.to_string()).collect()).unwrap_or_default()
+        }
+    }
+
+    fn show_location(&mut self, state: &Paused) {
+        let _ = writeln!(self.output, "Paused in {} at line {} (ip {:04x}, {})",
+            state.chunk.name(), state.line, state.ip, opcode_name(state.instruction));
+
+        if let Some(text) = self.source.get(state.line.wrapping_sub(1)) {
+            let _ = writeln!(self.output, "{:>5} | {}", state.line, text);
+        }
+    }
+
+    fn command(&mut self, breakpoints: &mut Breakpoints, state: &Paused, line: &str) -> Option<Resume> {
+        let (name, argument) = match line.find(char::is_whitespace) {
+            Some(split) => (&line[..split], line[split..].trim()),
+            None => (line, "")
+        };
+
+        match name {
+            "continue" | "c" => return Some(Resume::Continue),
+            "step" | "s" => return Some(Resume::StepInto),
+            "next" | "n" => return Some(Resume::StepOver),
+            "finish" => return Some(Resume::StepOut),
+            "quit" | "q" => return Some(Resume::Stop),
+            "break" | "b" if argument.is_empty() => {
+                let lines: Vec<String> = breakpoints.lines().iter().map(|l| l.to_string()).collect();
+                let _ = writeln!(self.output, "Breakpoints: {}", lines.join(", "));
+            },
+            "break" | "b" => match argument.parse() {
+                Ok(line) => match breakpoints.add(state.chunk, line) {
+                    Some(resolved) => { let _ = writeln!(self.output, "Breakpoint set at line {}", resolved); },
+                    None => { let _ = writeln!(self.output, "No code at or after line {}", line); }
+                },
+                Err(_) => { let _ = writeln!(self.output, "'{}' is not a line number", argument); }
+            },
+            "delete" if argument.is_empty() => breakpoints.clear(),
+            "delete" => match argument.parse() {
+                Ok(line) if breakpoints.remove(line) => { },
+                _ => { let _ = writeln!(self.output, "No breakpoint at line {}", argument); }
+            },
+            "backtrace" | "bt" => {
+                for (depth, frame) in state.frames.iter().rev().enumerate() {
+                    let _ = writeln!(self.output, "#{} {} at line {}", depth, frame.function, frame.line);
+                }
+            },
+            "locals" => {
+                let locals = state.chunk.locals_at(state.ip);
+
+                if locals.is_empty() {
+                    let _ = writeln!(self.output, "No locals in scope");
+                }
+
+                for local in locals {
+                    match state.stack.get(local.slot) {
+                        Some(value) => { let _ = writeln!(self.output, "{} = {:?}", local.name, value); },
+                        None => { let _ = writeln!(self.output, "{} is not initialized", local.name); }
+                    }
+                }
+            },
+            "stack" => {
+                for (slot, value) in state.stack.iter().enumerate() {
+                    let _ = writeln!(self.output, "sp[{}] => '{:?}'", slot, value);
+                }
+            },
+            "print" | "p" if !argument.is_empty() => {
+                let result = evaluate(argument);
+                let _ = writeln!(self.output, "{}", result);
+            },
+            "help" | "h" => { let _ = writeln!(self.output, "{}", HELP); },
+            "" => { },
+            _ => { let _ = writeln!(self.output, "Unknown command '{}', try help", line); }
+        }
+
+        return None;
+    }
+}
+
+impl<R: BufRead, W: Write> DebugFrontEnd for ConsoleFrontEnd<R, W> {
+    fn paused(&mut self, breakpoints: &mut Breakpoints, state: &Paused) -> Resume {
+        self.show_location(state);
+
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => return Resume::Stop,
+                Ok(_) => { }
+            }
+
+            if let Some(resume) = self.command(breakpoints, state, line.trim()) {
+                return resume;
+            }
+        }
+    }
+}
+
+// Expressions can't refer to anything in the paused frame yet, so they are
+// evaluated on a machine of their own.
+pub fn evaluate(expression: &str) -> String {
+    let chunk = match compiler::compile(expression, OptimizationLevel::None) {
+        Ok(chunk) => chunk,
+        Err(errors) => return errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("\n")
+    };
+
+    let mut vm = VirtualMachine::create();
+    match vm.run(&chunk) {
+        ExecutionResult::Ok => match vm.returned_value() {
+            Some(value) => format!("{:?}", value),
+            None => "nil".to_string()
+        },
+        // A machine of its own has no debugger to stop it.
+        ExecutionResult::Stopped => String::new(),
+        ExecutionResult::RuntimeError(message) | ExecutionResult::StaticError(message) => message.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct ScriptedFrontEnd {
+        breakpoints: Vec<usize>,
+        resumes: Vec<Resume>,
+        pauses: Rc<RefCell<Vec<(usize, usize)>>>
+    }
+
+    impl DebugFrontEnd for ScriptedFrontEnd {
+        fn paused(&mut self, breakpoints: &mut Breakpoints, state: &Paused) -> Resume {
+            for line in self.breakpoints.drain(..) {
+                breakpoints.add(state.chunk, line);
+            }
+
+            self.pauses.borrow_mut().push((state.line, state.ip));
+            if self.resumes.is_empty() { Resume::Continue } else { self.resumes.remove(0) }
+        }
+    }
+
+    // Lines 1, 3 and 5 each hold one operand; line 2 and 4 are blank.
+    const SOURCE: &str = "1 +\n\n2 *\n\n3";
+
+    fn debug(breakpoints: Vec<usize>, resumes: Vec<Resume>) -> (ExecutionResult, Vec<(usize, usize)>) {
+        let chunk = compiler::compile(SOURCE, OptimizationLevel::None).unwrap();
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let front_end = ScriptedFrontEnd { breakpoints, resumes, pauses: pauses.clone() };
+
+        let mut vm = VirtualMachine::create();
+        vm.set_debugger(Debugger::create(Box::new(front_end)));
+        let result = vm.run(&chunk);
+
+        let recorded = pauses.borrow().clone();
+        return (result, recorded);
+    }
+
+    #[test]
+    fn debugger_pauses_on_entry() {
+        let (result, pauses) = debug(vec![], vec![Resume::Continue]);
+
+        assert_eq!(result, ExecutionResult::Ok);
+        assert_eq!(pauses, vec![(1, 0)]);
+    }
+
+    #[test]
+    fn debugger_stops_at_breakpoints_moved_to_code() {
+        let (_, pauses) = debug(vec![4], vec![]);
+
+        assert_eq!(pauses, vec![(1, 0), (5, 4), (5, 8)]);
+    }
+
+    #[test]
+    fn debugger_steps_line_by_line() {
+        let (_, pauses) = debug(vec![], vec![Resume::StepOver, Resume::StepInto, Resume::StepOver, Resume::Continue]);
+
+        assert_eq!(pauses, vec![(1, 0), (3, 2), (5, 4), (3, 6)]);
+    }
+
+    #[test]
+    fn debugger_steps_out_of_the_script() {
+        let (result, pauses) = debug(vec![], vec![Resume::StepOut]);
+
+        assert_eq!(result, ExecutionResult::Ok);
+        assert_eq!(pauses, vec![(1, 0)]);
+    }
+
+    #[test]
+    fn debugger_stops_the_program() {
+        let (result, _) = debug(vec![], vec![Resume::Stop]);
+
+        assert_eq!(result, ExecutionResult::Stopped);
+    }
+
+    #[test]
+    fn console_front_end_runs_commands_until_resumed() {
+        let chunk = compiler::compile(SOURCE, OptimizationLevel::None).unwrap();
+        let input = "break 2\nbt\nprint 6 / 4\nbogus\nnext\n";
+        let mut output = Vec::new();
+
+        let resume = {
+            let mut front_end = ConsoleFrontEnd::create(input.as_bytes(), &mut output, Some(SOURCE));
+            let mut breakpoints = Breakpoints::default();
+            let frames = [StackFrame { function: "script".to_string(), ip: 0, line: 1 }];
+            front_end.paused(&mut breakpoints, &Paused {
+                chunk: &chunk,
+                ip: 0,
+                line: 1,
+                instruction: &Instruction::Constant(0),
+                stack: &[],
+                frames: &frames
+            })
+        };
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(resume, Resume::StepOver);
+        assert!(output.starts_with("Paused in script at line 1 (ip 0000, CONST)\n    1 | 1 +\n"));
+        assert!(output.contains("Breakpoint set at line 3\n"));
+        assert!(output.contains("#0 script at line 1\n"));
+        assert!(output.contains("1.5\n"));
+        assert!(output.contains("Unknown command 'bogus', try help\n"));
+    }
+}
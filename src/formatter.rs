@@ -0,0 +1,276 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use scanning::{Scanner, Token, TokenType};
+
+const INDENT: &str = "  ";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FormatError {
+    line_number: usize,
+    message: &'static str
+}
+
+struct Printer {
+    output: String,
+    depth: usize,
+    parentheses: usize,
+    line_started: bool,
+    line_break: bool,
+    // A trailing comment always ends its line, even where the next token
+    // would normally be joined onto it.
+    line_ended_by_comment: bool,
+    // Block comments in the middle of a line are set off by spaces.
+    after_block_comment: bool,
+    // Lines that start in the middle of a statement are indented once more.
+    in_statement: bool,
+    previous: Option<TokenType>,
+    previous_unary: bool,
+    previous_line: usize
+}
+
+// Lays the source out canonically: one statement per line, blocks
+// indented by depth, single spaces around binary operators and at most
+// one blank line in a row. Comments are kept where they were, either on
+// their own line or trailing the code they follow.
+pub fn format(source: &str) -> Result<String, Vec<FormatError>> {
+    let mut scanner = Scanner::create(source);
+    scanner.preserve_comments();
+
+    let mut printer = Printer {
+        output: String::with_capacity(source.len()),
+        depth: 0,
+        parentheses: 0,
+        line_started: false,
+        line_break: false,
+        line_ended_by_comment: false,
+        after_block_comment: false,
+        in_statement: false,
+        previous: None,
+        previous_unary: false,
+        previous_line: 1
+    };
+    let mut errors = Vec::new();
+
+    for token in scanner {
+        match token.token_type() {
+            TokenType::EndOfFile => {},
+            TokenType::Error(message) => errors.push(FormatError { line_number: token.line_number(), message }),
+            TokenType::Comment | TokenType::DocComment => printer.comment(&token),
+            _ => printer.token(&token)
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if printer.line_started {
+        printer.output.push('\n');
+    }
+
+    return Ok(printer.output);
+}
+
+impl FormatError {
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.line_number, self.message)
+    }
+}
+
+impl Printer {
+    fn comment(&mut self, token: &Token) {
+        let text = token.lexeme().trim_end();
+        let first_line = token.line_number() - token.lexeme().matches('\n').count();
+
+        if self.line_started && first_line == self.previous_line {
+            self.output.push(' ');
+        } else {
+            self.start_line(first_line, false);
+        }
+
+        self.output.push_str(text);
+        self.line_started = true;
+
+        // Only line comments run to the end of the line.
+        if text.starts_with("/*") {
+            self.after_block_comment = true;
+        } else {
+            self.line_break = true;
+            self.line_ended_by_comment = true;
+        }
+        self.previous_line = token.line_number();
+    }
+
+    fn token(&mut self, token: &Token) {
+        let token_type = token.token_type();
+        // Tokens report the line they end on.
+        let first_line = token.line_number() - token.lexeme().matches('\n').count();
+
+        if token_type == TokenType::RightBrace {
+            self.depth = self.depth.saturating_sub(1);
+            self.in_statement = false;
+            self.line_break |= self.line_started;
+        }
+
+        if token_type == TokenType::Else && self.previous == Some(TokenType::RightBrace) && !self.line_ended_by_comment {
+            self.line_break = false;
+        }
+
+        if self.line_started && !self.line_break {
+            if self.after_block_comment || self.space_before(token_type) {
+                self.output.push(' ');
+            }
+        } else {
+            self.start_line(first_line, token_type == TokenType::RightBrace);
+        }
+
+        self.output.push_str(token.lexeme());
+        self.line_started = true;
+        self.line_ended_by_comment = false;
+        self.after_block_comment = false;
+
+        self.previous_unary = match token_type {
+            TokenType::Bang => true,
+            TokenType::Minus => !self.previous.is_some_and(ends_operand),
+            _ => false
+        };
+
+        match token_type {
+            TokenType::LeftParen => self.parentheses += 1,
+            TokenType::RightParen => self.parentheses = self.parentheses.saturating_sub(1),
+            _ => {}
+        }
+
+        match token_type {
+            // Semicolons inside parentheses separate for loop clauses.
+            TokenType::SemiColon if self.parentheses > 0 => self.in_statement = true,
+            TokenType::SemiColon | TokenType::RightBrace => {
+                self.line_break = true;
+                self.in_statement = false;
+            },
+            TokenType::LeftBrace => {
+                self.depth += 1;
+                self.line_break = true;
+                self.in_statement = false;
+            },
+            _ => self.in_statement = true
+        }
+
+        self.previous = Some(token_type);
+        self.previous_line = token.line_number();
+    }
+
+    fn start_line(&mut self, source_line: usize, closes_block: bool) {
+        if self.line_started {
+            self.output.push('\n');
+        }
+
+        let after_gap = source_line > self.previous_line + 1;
+        let opens_block = self.previous == Some(TokenType::LeftBrace);
+        if !self.output.is_empty() && after_gap && !opens_block && !closes_block {
+            self.output.push('\n');
+        }
+
+        let depth = self.depth + if self.in_statement { 1 } else { 0 };
+        for _ in 0..depth {
+            self.output.push_str(INDENT);
+        }
+
+        self.line_started = false;
+        self.line_break = false;
+    }
+
+    fn space_before(&self, token_type: TokenType) -> bool {
+        match token_type {
+            TokenType::RightParen | TokenType::Comma | TokenType::SemiColon | TokenType::Dot
+                | TokenType::InterpolationMiddle | TokenType::InterpolationEnd => return false,
+            _ => {}
+        }
+
+        if self.previous_unary {
+            return false;
+        }
+
+        match (self.previous, token_type) {
+            (None, _) | (Some(TokenType::LeftParen), _) | (Some(TokenType::Dot), _)
+                | (Some(TokenType::InterpolationStart), _) | (Some(TokenType::InterpolationMiddle), _) => false,
+            // A call.
+            (Some(TokenType::Identifier), TokenType::LeftParen) | (Some(TokenType::RightParen), TokenType::LeftParen) => false,
+            _ => true
+        }
+    }
+}
+
+// Whether a token can end an operand, which makes a following minus a
+// binary operator rather than a negation.
+fn ends_operand(token_type: TokenType) -> bool {
+    matches!(token_type,
+        TokenType::Number | TokenType::String | TokenType::InterpolationEnd | TokenType::Identifier | TokenType::RightParen
+            | TokenType::True | TokenType::False | TokenType::Nil | TokenType::This)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_formats(source: &str, expected: &str) {
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted, "formatting is not idempotent");
+    }
+
+    #[test]
+    fn formatter_spaces_operators() {
+        assert_formats("1+2*  ( 3/-4 )", "1 + 2 * (3 / -4)\n");
+        assert_formats("1 - -2 - - ( 3 )", "1 - -2 - -(3)\n");
+        assert_formats("  !1 ==2", "!1 == 2\n");
+    }
+
+    #[test]
+    fn formatter_keeps_interpolated_expressions_tight() {
+        assert_formats("\"a ${ 1+2 } b ${\"c ${ -3 }\"}\" - 1", "\"a ${1 + 2} b ${\"c ${-3}\"}\" - 1\n");
+    }
+
+    #[test]
+    fn formatter_joins_expressions_split_across_lines() {
+        assert_formats("1 +\n\n   2\n", "1 + 2\n");
+    }
+
+    #[test]
+    fn formatter_breaks_lines_and_indents_blocks() {
+        assert_formats("{1;{2;}\n\n\n3;} {}", "{\n  1;\n  {\n    2;\n  }\n\n  3;\n}\n{\n}\n");
+        assert_formats("(1;2;3)", "(1; 2; 3)\n");
+    }
+
+    #[test]
+    fn formatter_keeps_comments() {
+        assert_formats(
+            "// leading\n1 +   // trailing\n2 ;// after\n\n\n   // own line   \n{3;}",
+            "// leading\n1 + // trailing\n  2; // after\n\n// own line\n{\n  3;\n}\n");
+    }
+
+    #[test]
+    fn formatter_keeps_block_and_doc_comments() {
+        assert_formats(
+            "/// Sums.\n(1 +/* one\n  two */2);  /* end */\n{ /* a /* b */ */ }",
+            "/// Sums.\n(1 + /* one\n  two */ 2); /* end */\n{ /* a /* b */ */\n}\n");
+    }
+
+    #[test]
+    fn formatter_rejects_lexical_errors() {
+        let errors = format("1 +\n%").err().unwrap();
+
+        assert_eq!(errors, vec![FormatError { line_number: 2, message: "Unexpected character" }]);
+        assert_eq!(errors[0].to_string(), "[line 2] Error: Unexpected character");
+    }
+}
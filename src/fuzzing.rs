@@ -0,0 +1,131 @@
+use std::io;
+use chunks::Chunk;
+use compiler;
+use compiler::OptimizationLevel;
+use debug;
+use debug::Format;
+use runtime::VirtualMachine;
+use scanning::{Scanner, TokenType};
+
+// Entry points for the fuzz targets in fuzz/, which feed them arbitrary
+// bytes. Whatever the bytes are, each one has to return without panicking
+// or running away; the assertions check what else must hold.
+
+// Enough instructions for any chunk that a fuzzer is likely to build, and
+// few enough that running one stays quick.
+const STEP_LIMIT: usize = 100_000;
+
+const OPTIMIZATION_LEVELS: [OptimizationLevel; 3] =
+    [OptimizationLevel::None, OptimizationLevel::ConstantFolding, OptimizationLevel::Peephole];
+
+// Scans the bytes as source text, with and without trivia. Every token
+// but EndOfFile takes up at least one byte, so there can never be more
+// tokens than bytes, and the trivia tokens put together are the source.
+pub fn scan(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+
+    let mut count = 0;
+    for token in Scanner::create(&source) {
+        count += 1;
+        assert!(count <= source.len() + 1, "the scanner stopped making progress");
+        assert!(token.lexeme_start() <= token.lexeme_end());
+    }
+
+    let mut scanner = Scanner::create(&source);
+    scanner.preserve_trivia();
+    let mut lexemes = String::with_capacity(source.len());
+    for token in scanner {
+        assert!(!token.lexeme().is_empty() || token.token_type() == TokenType::EndOfFile);
+        lexemes.push_str(token.lexeme());
+    }
+    assert_eq!(lexemes, source);
+}
+
+// Compiles the bytes as source text at every optimization level. Whatever
+// compiles has to pass verification and survive serialization.
+pub fn compile(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+
+    for &level in OPTIMIZATION_LEVELS.iter() {
+        if let Ok(chunk) = compiler::compile(&source, level) {
+            assert_eq!(chunk.verify(), Ok(()));
+            assert!(Chunk::deserialize(&chunk.serialize()).is_ok());
+        }
+    }
+}
+
+// Loads the bytes as a compiled chunk. Whatever loads has been verified,
+// so disassembling it and saving it again have to work.
+pub fn load(data: &[u8]) {
+    if let Ok(chunk) = Chunk::deserialize(data) {
+        debug::write_chunk(&chunk, Format::Text, &mut io::sink()).unwrap();
+        debug::write_chunk(&chunk, Format::Json, &mut io::sink()).unwrap();
+        assert!(Chunk::deserialize(&chunk.serialize()).is_ok());
+    }
+}
+
+// Loads the bytes as a compiled chunk and runs it with a step limit.
+pub fn execute(data: &[u8]) {
+    if let Ok(chunk) = Chunk::deserialize(data) {
+        let mut vm = VirtualMachine::create();
+        vm.set_step_limit(STEP_LIMIT);
+        vm.run(&chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use runtime::ExecutionResult;
+    use value::LoxValue;
+
+    // A serialized chunk with the given constants and raw code.
+    fn chunk_bytes(constants: &[LoxValue], code: &[u8]) -> Vec<u8> {
+        let mut chunk = Chunk::create("fuzz");
+        for constant in constants {
+            chunk.add_constant(constant.clone());
+        }
+        for &byte in code {
+            chunk.write(1, byte);
+        }
+        return chunk.serialize();
+    }
+
+    #[test]
+    fn fuzzing_scans_unterminated_literals_and_invalid_text() {
+        for &data in [&b"\""[..], b"\"${", b"\"${\"${}", b"/*/*", b"0x", b"1e+", b"\\u{", b"\xff\xfe\r", b"e\xcc\x81\xcc"].iter() {
+            scan(data);
+            compile(data);
+        }
+    }
+
+    #[test]
+    fn fuzzing_rejects_chunks_that_would_not_run() {
+        let cases = [
+            // Constant 0 followed by AddConstant 1, with only one constant.
+            (&[1, 0, 7, 1, 0][..], "Constant index out of range"),
+            // A constant instruction without its operand.
+            (&[1][..], "Invalid instruction in chunk"),
+            (&[0xff, 0][..], "Invalid instruction in chunk")
+        ];
+
+        for &(code, message) in cases.iter() {
+            let bytes = chunk_bytes(&[LoxValue::number(1.0)], code);
+
+            assert_eq!(Chunk::deserialize(&bytes).err(), Some(message));
+            load(&bytes);
+            execute(&bytes);
+        }
+    }
+
+    #[test]
+    fn fuzzing_stops_runs_at_the_step_limit() {
+        let chunk = Chunk::deserialize(&chunk_bytes(&[LoxValue::number(1.0)], &[1, 0, 2, 0])).unwrap();
+        let mut vm = VirtualMachine::create();
+        vm.set_step_limit(2);
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::RuntimeError("Step limit exceeded"));
+        vm.set_step_limit(3);
+        assert_eq!(vm.run(&chunk), ExecutionResult::Ok);
+    }
+}
@@ -0,0 +1,29 @@
+#![allow(clippy::needless_return)]
+
+extern crate rustyline;
+#[macro_use]
+extern crate serde_json;
+extern crate unicode_ident;
+extern crate unicode_normalization;
+extern crate unicode_script;
+extern crate unicode_security;
+#[cfg(test)]
+extern crate proptest;
+
+pub mod cli;
+pub mod compiler;
+pub mod dap;
+pub mod debug;
+pub mod debugger;
+pub mod scanning;
+pub mod chunks;
+pub mod formatter;
+pub mod fuzzing;
+pub mod lint;
+pub mod lsp;
+pub mod optimizer;
+pub mod protocol;
+pub mod repl;
+pub mod runtime;
+pub mod trace;
+pub mod value;
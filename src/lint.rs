@@ -0,0 +1,98 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use unicode_script::{Script, UnicodeScript};
+use unicode_security::MixedScript;
+use scanning::{Scanner, TokenType};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Warning {
+    line_number: usize,
+    start: usize,
+    length: usize,
+    name: String,
+    message: String
+}
+
+// Warns about identifiers whose letters come from more than one script,
+// such as a Cyrillic 'а' in an otherwise Latin name. Such a name can look
+// exactly like another one while being a different name.
+pub fn lint(source: &str) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+
+    for token in Scanner::create(source) {
+        if token.token_type() != TokenType::Identifier {
+            continue;
+        }
+
+        let name = token.lexeme();
+        // Characters used by several scripts, such as digits, the
+        // underscore and combining marks, fit in with any of them. Scripts
+        // written together, such as Han with Hiragana and Katakana in
+        // Japanese, count as one, as UTS #39 has them.
+        if name.is_single_script() {
+            continue;
+        }
+
+        let mut scripts: Vec<Script> = Vec::new();
+        for script in name.chars().map(|c| c.script()) {
+            if !matches!(script, Script::Common | Script::Inherited | Script::Unknown) && !scripts.contains(&script) {
+                scripts.push(script);
+            }
+        }
+
+        let names: Vec<&str> = scripts.iter().map(|script| script.full_name()).collect();
+        warnings.push(Warning {
+            line_number: token.line_number(),
+            start: token.lexeme_start(),
+            length: name.len(),
+            name: name.to_string(),
+            message: format!("Identifier mixes {} scripts", names.join(" and "))
+        });
+    }
+
+    return warnings;
+}
+
+impl Warning {
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    // The identifier as a byte offset into the source and a length in bytes.
+    pub fn span(&self) -> (usize, usize) {
+        (self.start, self.length)
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "[line {}] Warning at '{}': {}", self.line_number, self.name, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_warns_about_mixed_script_identifiers() {
+        let warnings = lint("caf\u{e9} + \u{43c}\u{438}\u{440} +\nv\u{430}lue + \u{3c0}_2");
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].to_string(), "[line 2] Warning at 'v\u{430}lue': Identifier mixes Latin and Cyrillic scripts");
+        assert_eq!(warnings[0].span(), (17, 6));
+    }
+
+    #[test]
+    fn lint_accepts_scripts_written_together() {
+        let warnings = lint("\u{5909}\u{6570}\u{306e}\u{5024} + \u{5909}\u{6570}\u{30a2}\u{30a4}\u{30c6}\u{30e0} + \
+                             \u{3072}\u{3089}\u{304c}\u{306a}\u{30ab}\u{30bf}\u{30ab}\u{30ca} + \u{97d3}\u{56fd}\u{c5b4} + \
+                             \u{6ce8}\u{97f3}\u{3105}");
+
+        assert_eq!(warnings, vec![]);
+    }
+}
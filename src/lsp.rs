@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use serde_json::Value;
+use compiler;
+use compiler::OptimizationLevel;
+use lint;
+use protocol::{read_message, write_message};
+use scanning;
+use scanning::TokenType;
+
+// JSON-RPC error codes for messages that are not JSON and for requests
+// the server does not implement.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const DIAGNOSTIC_ERROR: i64 = 1;
+const DIAGNOSTIC_WARNING: i64 = 2;
+
+// The semantic token legend; a token's type is its index in this list.
+const TOKEN_TYPES: &[&str] = &["keyword", "variable", "string", "number", "operator", "comment"];
+
+struct Server {
+    output: Box<dyn Write>,
+    documents: HashMap<String, String>,
+    optimization_level: OptimizationLevel,
+    shutdown_requested: bool
+}
+
+// Maps byte offsets into a document to zero-based lines and UTF-16
+// columns, which is how the protocol counts positions by default.
+struct LineIndex<'a> {
+    text: &'a str,
+    line_starts: Vec<usize>
+}
+
+// Serves Language Server Protocol requests until the client sends exit.
+// Returns whether the client asked for a shutdown first, as the protocol
+// uses that to decide the exit code.
+pub fn serve(mut input: Box<dyn BufRead>, output: Box<dyn Write>, optimization_level: OptimizationLevel) -> bool {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+        optimization_level,
+        shutdown_requested: false
+    };
+
+    loop {
+        let message = match read_message(&mut *input) {
+            Ok(Some(Ok(message))) => message,
+            // There is no telling which request it was, so the error has
+            // no id either.
+            Ok(Some(Err(error))) => {
+                let error = json!({ "code": PARSE_ERROR, "message": format!("Parse error: {}", error) });
+                server.send(json!({ "jsonrpc": "2.0", "id": Value::Null, "error": error }));
+                continue;
+            },
+            // A broken or closed connection ends the session like exit does.
+            Ok(None) | Err(_) => break
+        };
+
+        if message["method"] == "exit" {
+            break;
+        }
+        server.handle(&message);
+    }
+
+    return server.shutdown_requested;
+}
+
+impl Server {
+    fn handle(&mut self, message: &Value) {
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+
+        let result = match message["method"].as_str().unwrap_or("") {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true
+                    }
+                },
+                "serverInfo": { "name": "rlox" }
+            }),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Value::Null
+            },
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.update(uri, text);
+                return;
+            },
+            "textDocument/didChange" => {
+                // Only full document sync is advertised, so the last change
+                // holds the whole text.
+                if let Some(change) = params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    self.update(uri, change["text"].as_str().unwrap_or("").to_string());
+                }
+                return;
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }));
+                return;
+            },
+            "textDocument/semanticTokens/full" => {
+                let text = self.documents.get(&uri).map(String::as_str).unwrap_or("");
+                json!({ "data": semantic_tokens(text) })
+            },
+            method => {
+                if !message["id"].is_null() {
+                    let error = json!({ "code": METHOD_NOT_FOUND, "message": format!("Unsupported method '{}'", method) });
+                    self.send(json!({ "jsonrpc": "2.0", "id": message["id"], "error": error }));
+                }
+                return;
+            }
+        };
+
+        self.send(json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }));
+    }
+
+    fn update(&mut self, uri: String, text: String) {
+        let diagnostics = diagnostics(&text, self.optimization_level);
+        self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }));
+        self.documents.insert(uri, text);
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn send(&mut self, message: Value) {
+        let _ = write_message(&mut *self.output, &message);
+    }
+}
+
+fn diagnostics(text: &str, optimization_level: OptimizationLevel) -> Vec<Value> {
+    let index = LineIndex::create(text);
+    let diagnostic = |(start, length): (usize, usize), severity: i64, message: &str| json!({
+        "range": { "start": index.position(start), "end": index.position(start + length) },
+        "severity": severity,
+        "source": "rlox",
+        "message": message
+    });
+
+    let mut diagnostics: Vec<Value> = match compiler::compile(text, optimization_level) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter()
+            .map(|error| diagnostic(error.span(), DIAGNOSTIC_ERROR, error.message()))
+            .collect()
+    };
+
+    diagnostics.extend(lint::lint(text).iter()
+        .map(|warning| diagnostic(warning.span(), DIAGNOSTIC_WARNING, warning.message())));
+
+    return diagnostics;
+}
+
+// Encodes the tokens as the protocol's relative five-integer groups:
+// line delta, start delta, length, type and modifiers.
+fn semantic_tokens(text: &str) -> Vec<usize> {
+    let index = LineIndex::create(text);
+    let mut data = Vec::new();
+    let (mut previous_line, mut previous_column) = (0, 0);
+
+    for token in scanning::tokenize(text) {
+        let token_type = match token_type_index(token.token_type()) {
+            Some(token_type) => token_type,
+            None => continue
+        };
+
+        // Tokens may not span lines, so a multi-line token only covers
+        // its first line.
+        let first_line = token.lexeme().split('\n').next().unwrap_or("");
+        let (line, column) = index.line_and_column(token.lexeme_start());
+
+        let column_delta = if line == previous_line { column - previous_column } else { column };
+        data.extend_from_slice(&[line - previous_line, column_delta, first_line.encode_utf16().count(), token_type, 0]);
+
+        previous_line = line;
+        previous_column = column;
+    }
+
+    return data;
+}
+
+fn token_type_index(token_type: TokenType) -> Option<usize> {
+    let name = match token_type {
+        TokenType::And | TokenType::Class | TokenType::Else | TokenType::False
+            | TokenType::Fun | TokenType::For | TokenType::If | TokenType::Nil
+            | TokenType::Or | TokenType::Print | TokenType::Return | TokenType::Super
+            | TokenType::This | TokenType::True | TokenType::Var | TokenType::While => "keyword",
+        TokenType::Identifier => "variable",
+        TokenType::String | TokenType::InterpolationStart | TokenType::InterpolationMiddle
+            | TokenType::InterpolationEnd => "string",
+        TokenType::Number => "number",
+        TokenType::DocComment => "comment",
+        TokenType::Minus | TokenType::Plus | TokenType::Slash | TokenType::Star
+            | TokenType::Bang | TokenType::BangEqual | TokenType::Equal | TokenType::EqualEqual
+            | TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => "operator",
+        _ => return None
+    };
+
+    TOKEN_TYPES.iter().position(|&candidate| candidate == name)
+}
+
+impl<'a> LineIndex<'a> {
+    fn create(text: &'a str) -> LineIndex<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(offset, _)| offset + 1));
+
+        LineIndex { text, line_starts }
+    }
+
+    fn line_and_column(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let column = self.text[self.line_starts[line]..offset].encode_utf16().count();
+
+        (line, column)
+    }
+
+    fn position(&self, offset: usize) -> Value {
+        let (line, character) = self.line_and_column(offset);
+        json!({ "line": line, "character": character })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{exchange, frame, rpc_request as request};
+
+    const URI: &str = "file:///script.lox";
+
+    fn notification(method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "method": method, "params": params })
+    }
+
+    fn session(messages: Vec<Value>) -> (bool, Vec<Value>) {
+        return exchange(frame(&messages), |input, output| serve(input, output, OptimizationLevel::None));
+    }
+
+    #[test]
+    fn server_publishes_diagnostics_on_change() {
+        let (clean, messages) = session(vec![
+            request(1, "initialize", json!({ "capabilities": {} })),
+            notification("initialized", json!({})),
+            notification("textDocument/didOpen", json!({ "textDocument": { "uri": URI, "text": "1 +\n  (2 * 3" } })),
+            notification("textDocument/didChange", json!({
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "1 + (2 * 3)" }]
+            })),
+            request(2, "shutdown", Value::Null),
+            notification("exit", Value::Null)
+        ]);
+
+        assert!(clean);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0]["result"]["capabilities"]["semanticTokensProvider"]["legend"]["tokenTypes"][3], "number");
+        assert_eq!(messages[0]["result"]["capabilities"]["definitionProvider"], Value::Null);
+        assert_eq!(messages[1]["params"]["diagnostics"], json!([{
+            "range": { "start": { "line": 1, "character": 8 }, "end": { "line": 1, "character": 8 } },
+            "severity": 1,
+            "source": "rlox",
+            "message": "Expect ')' after expression"
+        }]));
+        assert_eq!(messages[2]["params"]["diagnostics"], json!([]));
+        assert_eq!(messages[3]["id"], 2);
+    }
+
+    #[test]
+    fn server_encodes_semantic_tokens() {
+        let (_, messages) = session(vec![
+            notification("textDocument/didOpen", json!({ "textDocument": { "uri": URI, "text": "(1 +\n\u{e9}2.5)" } })),
+            request(1, "textDocument/semanticTokens/full", json!({ "textDocument": { "uri": URI } })),
+            notification("exit", Value::Null)
+        ]);
+
+        assert_eq!(messages[1]["result"]["data"], json!([
+            0, 1, 1, 3, 0,
+            0, 2, 1, 4, 0,
+            1, 0, 2, 1, 0,
+            0, 3, 1, 3, 0
+        ]));
+    }
+
+    #[test]
+    fn server_rejects_requests_it_does_not_advertise() {
+        let (clean, messages) = session(vec![
+            notification("textDocument/didOpen", json!({ "textDocument": { "uri": URI, "text": "1" } })),
+            request(1, "textDocument/definition", json!({ "textDocument": { "uri": URI }, "position": { "line": 0, "character": 0 } })),
+            request(2, "textDocument/hover", json!({ "textDocument": { "uri": URI }, "position": { "line": 0, "character": 0 } })),
+            request(3, "textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } })),
+            request(4, "textDocument/rename", json!({})),
+            notification("exit", Value::Null)
+        ]);
+
+        assert!(!clean);
+        assert_eq!(messages.len(), 5);
+        for (id, message) in messages[1..].iter().enumerate() {
+            assert_eq!(message["id"], id + 1);
+            assert_eq!(message["error"]["code"], METHOD_NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn server_answers_bodies_that_are_not_json_and_carries_on() {
+        let mut input = b"Content-Length: 3\r\n\r\n{]}".to_vec();
+        input.extend(frame(&[request(1, "shutdown", Value::Null), notification("exit", Value::Null)]));
+
+        let (clean, messages) = exchange(input, |input, output| serve(input, output, OptimizationLevel::None));
+
+        assert!(clean);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["id"], Value::Null);
+        assert_eq!(messages[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(messages[1]["id"], 1);
+    }
+}
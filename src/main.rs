@@ -1,69 +1,275 @@
+#![allow(clippy::needless_return)]
 
-#[allow(dead_code)]
-mod debug;
+extern crate rlox;
 
-#[allow(dead_code)]
-mod scanning;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+use rlox::{cli, compiler, dap, debug, debugger, chunks, formatter, lint, lsp, repl, runtime, trace};
+use chunks::{Chunk, BYTECODE_MAGIC};
+use cli::{Command, Input, Options};
+use runtime::{ExecutionResult, VirtualMachine};
+use trace::Tracer;
 
-#[allow(dead_code)]
-mod chunks;
-
-#[allow(dead_code)]
-mod runtime;
-
-#[allow(dead_code)]
-fn main() -> Result<(), i32> {
-    let args: Vec<String> = std::env::args().collect();
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
     
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
-        println!("hey");
-    } else {
-       eprintln!("Usage: rlox [path]");
+    if let Err(code) = run(&args) {
+        process::exit(code);
     }
+}
+
+fn run(args: &[String]) -> Result<(), i32> {
+    let options = match cli::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, cli::USAGE);
+            return Err(cli::EX_USAGE);
+        }
+    };
     
-    Ok(())
+    match options.command {
+        Command::Repl => {
+            repl::run(create_vm(&options)?, options.optimization_level);
+            Ok(())
+        },
+        Command::Run(ref input) => run_input(input, &options),
+        Command::Compile { ref input, ref output } => compile_input(input, output, &options),
+        Command::Disasm { ref input, format } => {
+            let chunk = load_chunk(input, &options)?;
+            let stdout = io::stdout();
+            debug::write_chunk(&chunk, format, &mut stdout.lock())
+                .map_err(|_| cli::EX_IOERR)
+        },
+        Command::Check(ref inputs) => {
+            let mut result = Ok(());
+            
+            for input in inputs {
+                let source = read_source(input)?;
+                if compile_source(input, &source, &options).is_err() {
+                    result = Err(cli::EX_DATAERR);
+                }
+                // Warnings are shown but do not fail the check.
+                for warning in lint::lint(&source) {
+                    eprintln!("{}: {}", input, warning);
+                }
+            }
+            
+            result
+        },
+        Command::Debug(ref input) => debug_input(input, &options),
+        Command::Dap => {
+            dap::serve(Box::new(io::BufReader::new(io::stdin())), Box::new(io::stdout()), options.optimization_level);
+            Ok(())
+        },
+        Command::Lsp => {
+            // The protocol asks for a failing exit code when the client
+            // exits without shutting the server down first.
+            if lsp::serve(Box::new(io::BufReader::new(io::stdin())), Box::new(io::stdout()), options.optimization_level) {
+                Ok(())
+            } else {
+                Err(1)
+            }
+        },
+        Command::Fmt { ref inputs, check } => {
+            let mut result = Ok(());
+            
+            for input in inputs {
+                if let Err(code) = format_input(input, check) {
+                    result = Err(code);
+                }
+            }
+            
+            result
+        }
+    }
 }
 
-fn repl() {
-    use std::io::Write;;
+fn create_vm(options: &Options) -> Result<VirtualMachine, i32> {
+    let mut vm = VirtualMachine::with_stack_capacity(options.max_stack);
     
-    loop {
-        let mut input = String::new();
+    if options.trace {
+        let sink: Box<dyn Write> = match options.trace_file {
+            Some(ref path) => match fs::File::create(path) {
+                Ok(file) => Box::new(io::BufWriter::new(file)),
+                Err(error) => {
+                    eprintln!("Could not create {}: {}", path, error);
+                    return Err(cli::EX_CANTCREAT);
+                }
+            },
+            None => Box::new(io::stderr())
+        };
+        
+        let mut tracer = Tracer::create(sink);
         
-        print!("> ");
-        std::io::stdout().flush().expect("There was a problem writing to console");;
-        std::io::stdin()
-            .read_line(&mut input)
-            .expect("There was a problem reading your input");
+        if let Some(ref function) = options.trace_function {
+            tracer.filter_function(function);
+        }
         
-        if input.len() == 1 {
-            break;
+        if let Some((first, last)) = options.trace_lines {
+            tracer.filter_lines(first, last);
         }
         
-        interpret(&input);
+        vm.set_tracer(tracer);
+    }
+    
+    return Ok(vm);
+}
+
+fn run_input(input: &Input, options: &Options) -> Result<(), i32> {
+    let chunk = load_chunk(input, options)?;
+    run_chunk(create_vm(options)?, &chunk)
+}
+
+fn run_chunk(mut vm: VirtualMachine, chunk: &Chunk) -> Result<(), i32> {
+    match vm.run(chunk) {
+        ExecutionResult::Ok => {
+            if let Some(value) = vm.returned_value() {
+                println!("{:?}", value);
+            }
+            Ok(())
+        },
+        ExecutionResult::Stopped => Ok(()),
+        ExecutionResult::RuntimeError(message) => {
+            eprintln!("{}", message);
+            Err(cli::EX_SOFTWARE)
+        },
+        ExecutionResult::StaticError(message) => {
+            eprintln!("{}", message);
+            Err(cli::EX_DATAERR)
+        }
     }
 }
 
-fn run_file(path_name: &str) {
-    use std::fs;
-    use std::io::Read;
+fn debug_input(input: &Input, options: &Options) -> Result<(), i32> {
+    if let Input::Stdin = input {
+        eprintln!("The debugger reads its commands from stdin, so the script must come from a file");
+        return Err(cli::EX_USAGE);
+    }
     
-    let mut file = fs::File::open(path_name)
-        .expect("File not found");
+    let bytes = read_input(input)?;
+    let source = if bytes.starts_with(BYTECODE_MAGIC) { None } else { String::from_utf8(bytes.clone()).ok() };
     
-    let mut input = String::new();
-    file.read_to_string(&mut input)
-        .expect("File contents are not accessible");
+    let stdin = io::stdin();
+    let front_end = debugger::ConsoleFrontEnd::create(stdin.lock(), io::stdout(), source.as_deref());
+    let mut vm = create_vm(options)?;
+    vm.set_debugger(debugger::Debugger::create(Box::new(front_end)));
     
-    interpret(&input);
+    run_chunk(vm, &chunk_from_bytes(input, bytes, options)?)
 }
 
-#[allow(unused_variables)]
-fn interpret(input: &str) -> runtime::ExecutionResult {
+fn compile_input(input: &Input, output: &Option<String>, options: &Options) -> Result<(), i32> {
+    let source = read_source(input)?;
+    let bytecode = compile_source(input, &source, options)?.serialize();
+    
+    let destination = match (output, input) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Input::Path(path)) => Some(Path::new(path).with_extension("loxc").to_string_lossy().into_owned()),
+        (None, Input::Stdin) => None
+    };
     
-    runtime::ExecutionResult::Ok
+    let written = match destination {
+        Some(ref path) => fs::write(path, &bytecode),
+        None => io::stdout().write_all(&bytecode)
+    };
+    
+    written.map_err(|error| {
+        eprintln!("Could not write {}: {}", destination.unwrap_or_else(|| "<stdout>".to_string()), error);
+        cli::EX_IOERR
+    })
 }
 
+fn format_input(input: &Input, check: bool) -> Result<(), i32> {
+    let source = read_source(input)?;
+    
+    let formatted = formatter::format(&source).map_err(|errors| {
+        for error in errors {
+            eprintln!("{}: {}", input, error);
+        }
+        cli::EX_DATAERR
+    })?;
+    
+    if check {
+        if formatted == source {
+            return Ok(());
+        }
+        
+        // Like other formatters' check modes, report a file that would
+        // change with a plain failure status.
+        eprintln!("{} is not formatted", input);
+        return Err(1);
+    }
+    
+    let written = match input {
+        Input::Path(path) if formatted != source => fs::write(path, &formatted),
+        Input::Path(_) => Ok(()),
+        Input::Stdin => io::stdout().write_all(formatted.as_bytes())
+    };
+    
+    written.map_err(|error| {
+        eprintln!("Could not write {}: {}", input, error);
+        cli::EX_IOERR
+    })
+}
+
+// Compiled bytecode runs as is; anything else is compiled as Lox source.
+fn load_chunk(input: &Input, options: &Options) -> Result<Chunk, i32> {
+    let bytes = read_input(input)?;
+    chunk_from_bytes(input, bytes, options)
+}
+
+fn chunk_from_bytes(input: &Input, bytes: Vec<u8>, options: &Options) -> Result<Chunk, i32> {
+    if bytes.starts_with(BYTECODE_MAGIC) {
+        return Chunk::deserialize(&bytes).map_err(|message| {
+            eprintln!("{}: {}", input, message);
+            cli::EX_DATAERR
+        });
+    }
+    
+    let source = decode_source(input, bytes)?;
+    compile_source(input, &source, options)
+}
+
+fn compile_source(input: &Input, source: &str, options: &Options) -> Result<Chunk, i32> {
+    compiler::compile(source, options.optimization_level).map_err(|errors| {
+        for error in errors {
+            eprintln!("{}: {}", input, error);
+        }
+        cli::EX_DATAERR
+    })
+}
+
+fn read_source(input: &Input) -> Result<String, i32> {
+    let bytes = read_input(input)?;
+    decode_source(input, bytes)
+}
+
+fn decode_source(input: &Input, bytes: Vec<u8>) -> Result<String, i32> {
+    String::from_utf8(bytes).map_err(|_| {
+        eprintln!("{}: source is not valid UTF-8", input);
+        cli::EX_DATAERR
+    })
+}
+
+fn read_input(input: &Input) -> Result<Vec<u8>, i32> {
+    let mut bytes = Vec::new();
+    
+    let read = match input {
+        Input::Stdin => io::stdin().read_to_end(&mut bytes).map(|_| ()),
+        Input::Path(path) => fs::File::open(path).and_then(|mut file| file.read_to_end(&mut bytes).map(|_| ()))
+    };
+    
+    match read {
+        Ok(()) => Ok(bytes),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            eprintln!("Could not find {}", input);
+            Err(cli::EX_NOINPUT)
+        },
+        Err(error) => {
+            eprintln!("Could not read {}: {}", input, error);
+            Err(cli::EX_IOERR)
+        }
+    }
+}
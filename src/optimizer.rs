@@ -0,0 +1,181 @@
+use chunks::*;
+
+// Rewrites a finished chunk, folding a Constant followed by Negate into a
+// negated constant and fusing a Constant followed by arithmetic into the
+// matching superinstruction. Each rewritten instruction keeps the line of the
+// instruction which could fail at runtime, so errors still point at the
+// operator. Nothing jumps yet, so any pair of instructions is safe to fuse.
+// A chunk with code or constants the pass cannot make sense of is returned
+// unchanged, for verification to reject.
+pub fn peephole(chunk: &Chunk) -> Chunk {
+    let mut constants = Chunk::create(chunk.name());
+    
+    for constant in chunk.constants() {
+        constants.add_constant(constant.clone());
+    }
+    
+    let mut instructions: Vec<(usize, Instruction)> = Vec::new();
+    let mut bytecode = chunk.code();
+    let mut offset = 0;
+    
+    loop {
+        let (bytes_consumed, instruction) = Instruction::from_bytecode(&mut bytecode);
+        let instruction = match instruction {
+            Some(instruction) => instruction,
+            None if bytes_consumed == 0 => break,
+            None => return chunk.clone()
+        };
+        let line = chunk.line(offset).unwrap_or(0);
+        offset += bytes_consumed;
+        
+        let fused = match (instructions.last(), instruction) {
+            (Some(&(constant_line, Instruction::Constant(index))), Instruction::Negate) => 
+                fold_negation(&mut constants, index).map(|folded| (constant_line, folded)),
+            (Some(&(_, Instruction::Constant(index))), Instruction::Add) => 
+                Some((line, Instruction::AddConstant(index))),
+            (Some(&(_, Instruction::Constant(index))), Instruction::Subtract) => 
+                Some((line, Instruction::SubtractConstant(index))),
+            (Some(&(_, Instruction::Constant(index))), Instruction::Multiply) => 
+                Some((line, Instruction::MultiplyConstant(index))),
+            (Some(&(_, Instruction::Constant(index))), Instruction::Divide) => 
+                Some((line, Instruction::DivideConstant(index))),
+            _ => None
+        };
+        
+        match fused {
+            Some(replacement) => {
+                instructions.pop();
+                instructions.push(replacement);
+            },
+            None => instructions.push((line, instruction))
+        }
+    }
+    
+    return compact(&constants, &instructions).unwrap_or_else(|| chunk.clone());
+}
+
+fn fold_negation(chunk: &mut Chunk, index: u8) -> Option<Instruction> {
+    let negated = chunk.constant(index)?.negate()?;
+    
+    chunk.add_constant(negated).map(Instruction::Constant)
+}
+
+// Writes out the rewritten instructions with only the constants they still
+// use, since folding a negation leaves the constant it negated behind.
+// Constants keep their relative order.
+fn compact(constants: &Chunk, instructions: &[(usize, Instruction)]) -> Option<Chunk> {
+    let mut used = vec![false; constants.constants_len()];
+    for &(_, instruction) in instructions {
+        if let Some(index) = instruction.constant_index() {
+            *used.get_mut(index as usize)? = true;
+        }
+    }
+    
+    let mut optimized = Chunk::create(constants.name());
+    let mut renumbered = vec![0; used.len()];
+    for (index, constant) in constants.constants().enumerate() {
+        if used[index] {
+            renumbered[index] = optimized.add_constant(constant.clone())?;
+        }
+    }
+    
+    for &(line, instruction) in instructions {
+        let instruction = match instruction.constant_index() {
+            Some(index) => instruction.with_constant_index(renumbered[index as usize]),
+            None => instruction
+        };
+        for byte in instruction.as_bytecode() {
+            optimized.write(line, byte);
+        }
+    }
+    
+    return Some(optimized);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use runtime::LoxValue;
+
+    fn decode(chunk: &Chunk) -> Vec<(usize, Instruction)> {
+        let mut instructions = Vec::new();
+        let mut bytecode = chunk.code();
+        let mut offset = 0;
+        
+        while let (bytes_consumed, Some(instruction)) = Instruction::from_bytecode(&mut bytecode) {
+            instructions.push((chunk.line(offset).unwrap(), instruction));
+            offset += bytes_consumed;
+        }
+        
+        return instructions;
+    }
+
+    #[test]
+    fn peephole_fuses_constant_arithmetic() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0), LoxValue::number(2.0), LoxValue::number(3.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(1)),
+            (2, Instruction::Add),
+            (2, Instruction::Constant(2)),
+            (3, Instruction::Multiply),
+            (4, Instruction::Return)
+        ]);
+
+        let optimized = peephole(&chunk);
+
+        assert_eq!(decode(&optimized), vec![
+            (1, Instruction::Constant(0)),
+            (2, Instruction::AddConstant(1)),
+            (3, Instruction::MultiplyConstant(2)),
+            (4, Instruction::Return)
+        ]);
+    }
+
+    #[test]
+    fn peephole_folds_negated_constants() {
+        let chunk = Chunk::assemble(&[LoxValue::number(5.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Negate),
+            (1, Instruction::Negate),
+            (2, Instruction::Return)
+        ]);
+
+        let optimized = peephole(&chunk);
+        let instructions = decode(&optimized);
+
+        assert_eq!(instructions, vec![
+            (1, Instruction::Constant(0)),
+            (2, Instruction::Return)
+        ]);
+        assert_eq!(optimized.constants_len(), 1);
+        assert_eq!(optimized.constant(0).unwrap().as_number(), Some(5.0));
+    }
+
+    #[test]
+    fn peephole_leaves_unfusable_sequences_alone() {
+        let instructions = vec![
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Negate),
+            (1, Instruction::Constant(0)),
+            (2, Instruction::Return)
+        ];
+        let chunk = Chunk::assemble(&[LoxValue::boolean(true)], &instructions);
+
+        let optimized = peephole(&chunk);
+
+        assert_eq!(decode(&optimized), instructions);
+    }
+
+    #[test]
+    fn peephole_leaves_chunks_it_cannot_read_alone() {
+        let mut chunk = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Negate)
+        ]);
+        chunk.write(2, 0xff);
+        let missing = Chunk::assemble(&[], &[(1, Instruction::Constant(3)), (1, Instruction::Negate)]);
+
+        assert_eq!(peephole(&chunk).serialize(), chunk.serialize());
+        assert_eq!(peephole(&missing).serialize(), missing.serialize());
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(test)]
+use std::cell::RefCell;
+use std::io;
+use std::io::{BufRead, Write};
+#[cfg(test)]
+use std::rc::Rc;
+use serde_json;
+use serde_json::Value;
+
+// Bodies are read whole, so their size is capped to keep a bad header
+// from asking for any amount of memory.
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
+// The base protocol shared by the Debug Adapter and Language Server
+// protocols: a JSON body preceded by a Content-Length header and a blank line.
+// A body that is not JSON comes back as an error inside the result, since
+// the next message can still be read after it; broken framing cannot be
+// read past and is an I/O error.
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<serde_json::Result<Value>>> {
+    let mut content_length = None;
+    let mut headers_seen = false;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            if headers_seen {
+                break;
+            }
+            continue;
+        }
+        headers_seen = true;
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            let length = value.trim().parse::<usize>().map_err(|_| invalid_data("Invalid Content-Length header"))?;
+            content_length = Some(length);
+        }
+    }
+
+    let content_length = content_length.ok_or_else(|| invalid_data("Missing Content-Length header"))?;
+    if content_length > MAX_CONTENT_LENGTH {
+        return Err(invalid_data("Message body is too large"));
+    }
+
+    let mut body = vec![0u8; content_length];
+    input.read_exact(&mut body)?;
+
+    return Ok(Some(serde_json::from_slice(&body)));
+}
+
+pub fn write_message(output: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+pub fn frame(messages: &[Value]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for message in messages {
+        write_message(&mut bytes, message).unwrap();
+    }
+    return bytes;
+}
+
+#[cfg(test)]
+pub fn unframe(mut bytes: &[u8]) -> Vec<Value> {
+    let mut messages = Vec::new();
+    while let Some(message) = read_message(&mut bytes).unwrap() {
+        messages.push(message.unwrap());
+    }
+    return messages;
+}
+
+// An output a test can still read after handing it over to be written to.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Serves the framed input and returns what the server returned along with
+// the messages it wrote back.
+#[cfg(test)]
+pub fn exchange<T, F>(input: Vec<u8>, serve: F) -> (T, Vec<Value>)
+    where F: FnOnce(Box<dyn BufRead>, Box<dyn Write>) -> T {
+    let output = SharedBuffer::default();
+    let result = serve(Box::new(io::Cursor::new(input)), Box::new(output.clone()));
+    return (result, unframe(&output.contents()));
+}
+
+// Requests as the two protocols frame them: JSON-RPC calls a method with
+// params, the Debug Adapter Protocol a command with arguments.
+#[cfg(test)]
+pub fn rpc_request(id: i64, method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+}
+
+#[cfg(test)]
+pub fn dap_request(seq: i64, command: &str, arguments: Value) -> Value {
+    json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_round_trips_framed_messages() {
+        let messages = vec![json!({"seq": 1, "text": "caf\u{e9}"}), json!([1, 2, 3])];
+
+        let bytes = frame(&messages);
+
+        assert!(bytes.starts_with(b"Content-Length: 24\r\n\r\n"));
+        assert_eq!(unframe(&bytes), messages);
+    }
+
+    #[test]
+    fn protocol_ignores_other_headers() {
+        let bytes = b"Content-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+
+        assert_eq!(unframe(bytes), vec![json!({})]);
+    }
+
+    #[test]
+    fn protocol_reads_past_bodies_that_are_not_json() {
+        let mut bytes = &b"Content-Length: 3\r\n\r\n{]}Content-Length: 2\r\n\r\n{}"[..];
+
+        assert!(read_message(&mut bytes).unwrap().unwrap().is_err());
+        assert_eq!(read_message(&mut bytes).unwrap().unwrap().unwrap(), json!({}));
+        assert!(read_message(&mut bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn protocol_rejects_missing_and_oversized_lengths() {
+        let cases: [(&[u8], &str); 3] = [
+            (b"Content-Type: application/json\r\n\r\n{}", "Missing Content-Length header"),
+            (b"Content-Length: lots\r\n\r\n{}", "Invalid Content-Length header"),
+            (b"Content-Length: 18446744073709551615\r\n\r\n{}", "Message body is too large")
+        ];
+
+        for &(mut bytes, message) in cases.iter() {
+            let error = read_message(&mut bytes).err().unwrap();
+
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
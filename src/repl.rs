@@ -0,0 +1,216 @@
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::time::Instant;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use chunks::Chunk;
+use compiler;
+use compiler::OptimizationLevel;
+use debug;
+use runtime::{ExecutionResult, VirtualMachine};
+use scanning::{Scanner, TokenType};
+
+const HISTORY_FILE: &str = ".rlox_history";
+
+const HELP: &str = "\
+:diagnostics    toggle tracing of each instruction as it executes
+:disasm         disassemble the last compiled input
+:stack          show the value stack
+:globals        show the global variables
+:gc             collect garbage and show heap statistics
+:load <path>    run a file in this session
+:time <expr>    evaluate an expression and report how long it took
+:help           show this message";
+
+struct Session {
+    // One machine for the whole session, so nothing defined by an earlier
+    // input is lost when a later one fails.
+    vm: VirtualMachine,
+    last_chunk: Option<Chunk>,
+    optimization_level: OptimizationLevel
+}
+
+pub fn run(vm: VirtualMachine, optimization_level: OptimizationLevel) {
+    let mut editor = DefaultEditor::new().expect("There was a problem opening the console");
+    let history = history_path();
+
+    if let Some(ref path) = history {
+        // There's no history the first time the REPL runs.
+        let _ = editor.load_history(path);
+    }
+
+    let mut session = Session {
+        vm,
+        last_chunk: None,
+        optimization_level
+    };
+    let mut input = String::new();
+
+    loop {
+        let prompt = if input.is_empty() { "> " } else { ". " };
+
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if input.is_empty() && line.trim_start().starts_with(':') {
+                    let _ = editor.add_history_entry(line.trim());
+                    session.command(line.trim());
+                    continue;
+                }
+
+                input.push_str(&line);
+                input.push('\n');
+
+                if needs_continuation(&input) {
+                    continue;
+                }
+
+                let entry = mem::take(&mut input);
+                if entry.trim().is_empty() {
+                    continue;
+                }
+
+                let _ = editor.add_history_entry(entry.trim_end());
+                session.evaluate(&entry);
+            },
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("There was a problem reading your input: {}", error);
+                break;
+            }
+        }
+    }
+
+    if let Some(ref path) = history {
+        if let Err(error) = editor.save_history(path) {
+            eprintln!("Could not save history to {}: {}", path.display(), error);
+        }
+    }
+}
+
+// Input continues onto the next line while it has more opening parentheses
+// or braces than closing ones.
+pub fn needs_continuation(input: &str) -> bool {
+    let mut depth: isize = 0;
+
+    for token in Scanner::create(input) {
+        match token.token_type() {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            // Only the end of the input leaves these unterminated, so a
+            // later line can still close them.
+            TokenType::Error("Unterminated string") | TokenType::Error("Unterminated block comment") => return true,
+            _ => { }
+        }
+    }
+
+    return depth > 0;
+}
+
+impl Session {
+    fn command(&mut self, line: &str) {
+        let (name, argument) = match line.find(char::is_whitespace) {
+            Some(split) => (&line[..split], line[split..].trim()),
+            None => (line, "")
+        };
+
+        match name {
+            ":diagnostics" => {
+                if self.vm.diagnostics_enabled() {
+                    self.vm.disable_diagnostics();
+                    println!("Diagnostics disabled");
+                } else {
+                    self.vm.enable_diagnostics();
+                    println!("Diagnostics enabled");
+                }
+            },
+            ":disasm" => match self.last_chunk {
+                Some(ref chunk) => debug::dissassemble_chunk(chunk),
+                None => println!("Nothing has been compiled yet")
+            },
+            ":stack" => {
+                if self.vm.stack().is_empty() {
+                    println!("The stack is empty");
+                }
+
+                for (slot, value) in self.vm.stack().iter().enumerate() {
+                    println!("sp[{}] => '{:?}'", slot, value);
+                }
+            },
+            ":globals" => println!("The virtual machine does not support global variables yet"),
+            ":gc" => println!("The virtual machine does not allocate on a collected heap yet"),
+            ":load" if !argument.is_empty() => match fs::read_to_string(argument) {
+                Ok(source) => self.evaluate(&source),
+                Err(error) => eprintln!("Could not read {}: {}", argument, error)
+            },
+            ":time" if !argument.is_empty() => {
+                let started = Instant::now();
+                self.evaluate(argument);
+                println!("Took {:?}", started.elapsed());
+            },
+            ":load" | ":time" => eprintln!("{} needs an argument, try :help", name),
+            ":help" => println!("{}", HELP),
+            _ => eprintln!("Unknown command '{}', try :help", line)
+        }
+    }
+
+    fn evaluate(&mut self, input: &str) {
+        let chunk = match compiler::compile(input, self.optimization_level) {
+            Ok(chunk) => chunk,
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{}", error);
+                }
+                return;
+            }
+        };
+
+        match self.vm.run(&chunk) {
+            ExecutionResult::Ok => {
+                if let Some(value) = self.vm.returned_value() {
+                    println!("{:?}", value);
+                }
+            },
+            ExecutionResult::Stopped => { },
+            ExecutionResult::RuntimeError(message) | ExecutionResult::StaticError(message) => {
+                eprintln!("{}", message);
+            }
+        }
+
+        self.last_chunk = Some(chunk);
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repl_continues_while_delimiters_are_open() {
+        assert!(needs_continuation("(1 +\n"));
+        assert!(needs_continuation("{ (1)\n"));
+        assert!(needs_continuation("((1 + 2)\n"));
+    }
+
+    #[test]
+    fn repl_continues_unterminated_strings_and_comments() {
+        assert!(needs_continuation("\"first line\n"));
+        assert!(needs_continuation("1 + /* a ( comment\n"));
+        assert!(!needs_continuation("\"first\nsecond\"\n"));
+        assert!(!needs_continuation("/* a\n comment */ 1\n"));
+    }
+
+    #[test]
+    fn repl_evaluates_balanced_input() {
+        assert!(!needs_continuation("1 + 2\n"));
+        assert!(!needs_continuation("(1 +\n 2)\n"));
+        assert!(!needs_continuation("1 + 2)\n"));
+        assert!(!needs_continuation("// ( in a comment\n"));
+    }
+}
@@ -1,64 +1,174 @@
-use std::fmt::{Debug, Formatter, Result};
 use chunks::*;
 use debug::*;
+use debugger::{Debugger, Paused, StackFrame};
+use trace::{TraceStep, Tracer};
+pub use value::LoxValue;
 
-#[derive(Clone, Copy)]
-pub enum LoxValue {
-    Number(f64),
-    String
-}
-
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ExecutionResult {
     Ok,
+    // The debugger was told to quit, which is not a failure of the program.
+    Stopped,
     StaticError(&'static str),
     RuntimeError(&'static str)
 }
 
+pub const DEFAULT_STACK_CAPACITY: usize = 256;
+// The stack is allocated up front, so its size is capped well below what
+// would exhaust memory.
+pub const MAX_STACK_CAPACITY: usize = 1 << 20;
+
 pub struct VirtualMachine {
     ip: usize,
+    // Allocated once up front and never grown past stack_capacity, so the
+    // storage backing the stack never moves while the machine is running.
     stack: Vec<LoxValue>,
+    stack_capacity: usize,
+    returned_value: Option<LoxValue>,
     diagnostics_enabled: bool,
-    failure: Option<ExecutionResult>
+    tracer: Option<Tracer>,
+    debugger: Option<Debugger>,
+    // The most instructions a single run may execute, for running code
+    // that is not trusted to finish.
+    step_limit: Option<usize>
 }
 
 impl VirtualMachine {
     pub fn create() -> VirtualMachine {
+        VirtualMachine::with_stack_capacity(DEFAULT_STACK_CAPACITY)
+    }
+    
+    pub fn with_stack_capacity(stack_capacity: usize) -> VirtualMachine {
         VirtualMachine {
             ip: 0,
-            stack: Vec::new(),
+            stack: Vec::with_capacity(stack_capacity),
+            stack_capacity,
+            returned_value: None,
             diagnostics_enabled: false,
-            failure: None
+            tracer: None,
+            debugger: None,
+            step_limit: None
         }
     }
     
+    pub fn stack_capacity(&self) -> usize {
+        self.stack_capacity
+    }
+    
+    pub fn returned_value(&self) -> Option<&LoxValue> {
+        self.returned_value.as_ref()
+    }
+    
     pub fn enable_diagnostics(&mut self) {
         self.diagnostics_enabled = true;
     }
     
+    pub fn disable_diagnostics(&mut self) {
+        self.diagnostics_enabled = false;
+    }
+    
+    pub fn diagnostics_enabled(&self) -> bool {
+        self.diagnostics_enabled
+    }
+    
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+    
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+    
+    pub fn set_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+    
+    pub fn take_debugger(&mut self) -> Option<Debugger> {
+        self.debugger.take()
+    }
+    
+    pub fn set_step_limit(&mut self, steps: usize) {
+        self.step_limit = Some(steps);
+    }
+    
+    pub fn step_limit(&self) -> Option<usize> {
+        self.step_limit
+    }
+    
+    pub fn stack(&self) -> &[LoxValue] {
+        self.stack.as_slice()
+    }
+    
     pub fn run(&mut self, chunk: &Chunk) -> ExecutionResult {
-        if let Some(previous_failure) = self.failure {
-            previous_failure
-        } else {
-            let result = self.run_imp(chunk);
-            
-            if result != ExecutionResult::Ok {
-                self.failure = Some(result);
-            }
-            
-            return result;
+        let result = self.run_imp(chunk);
+        
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.flush();
+        }
+        
+        // A failed run abandons whatever it had in flight, but anything the
+        // machine holds on to between runs stays put for the next one.
+        if result != ExecutionResult::Ok {
+            self.reset();
         }
+        
+        return result;
+    }
+    
+    pub fn reset(&mut self) {
+        self.ip = 0;
+        self.stack.clear();
     }
     
     fn run_imp(&mut self, chunk: &Chunk) -> ExecutionResult {
         self.ip = 0;
+        self.returned_value = None;
         let mut bytecode = chunk.code();
+        let mut steps = 0;
         
         if self.diagnostics_enabled {
             dissassemble_chunk(chunk);
         }
         
         while let (consumed, Some(instruction)) = Instruction::from_bytecode(&mut bytecode) {
+            if self.step_limit.is_some_and(|limit| steps >= limit) {
+                return ExecutionResult::RuntimeError("Step limit exceeded");
+            }
+            steps += 1;
+            let line = chunk.line(self.ip).unwrap_or(0);
+            
+            if let Some(ref mut debugger) = self.debugger {
+                // There is only ever the one frame until functions can be called.
+                let frames = [StackFrame {
+                    function: chunk.name().to_owned(),
+                    ip: self.ip,
+                    line
+                }];
+                
+                let keep_running = debugger.before_instruction(&Paused {
+                    chunk,
+                    ip: self.ip,
+                    line,
+                    instruction: &instruction,
+                    stack: &self.stack,
+                    frames: &frames
+                });
+                
+                if !keep_running {
+                    return ExecutionResult::Stopped;
+                }
+            }
+            
+            if let Some(ref mut tracer) = self.tracer {
+                tracer.step(&TraceStep {
+                    frame: chunk.name(),
+                    ip: self.ip,
+                    line,
+                    instruction: &instruction,
+                    stack: &self.stack
+                });
+            }
+            
             self.ip += consumed;
             
             if self.diagnostics_enabled {
@@ -72,20 +182,31 @@ impl VirtualMachine {
             
             match instruction {
                 Instruction::Return => { 
-                    let popped = self.pop();
-                    match popped {
-                        Some(value) => println!("sp[0] => '{:?}'", value),
-                        None => println!("sp[0] => 'nil'")
-                    };
+                    self.returned_value = self.pop();
+                    
+                    if self.diagnostics_enabled {
+                        match self.returned_value {
+                            Some(ref value) => println!("sp[0] => '{:?}'", value),
+                            None => println!("sp[0] => 'nil'")
+                        };
+                    }
                     return ExecutionResult::Ok;
                 },
                 Instruction::Constant(index) => { 
-                    self.push(chunk.constant(index).clone()); 
+                    let constant = match constant(chunk, index) {
+                        Ok(constant) => constant,
+                        Err(failure) => return failure
+                    };
+                    if let Err(failure) = self.push(constant) {
+                        return failure;
+                    }
                 },
                 Instruction::Negate => {
                     if let Some(value) = self.pop() {
                         if let Some(computed) = value.negate() {
-                            self.push(computed);
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
                         } else {
                             return ExecutionResult::RuntimeError("Only numbers can be negated");
                         }
@@ -96,9 +217,11 @@ impl VirtualMachine {
                 Instruction::Add => {
                     if let Some((left, right)) = self.pop_two() {
                         if let Some(computed)= left.add(&right) {
-                            self.push(computed)
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
                         } else {
-                            return ExecutionResult::RuntimeError("Only two numbers can be added");
+                            return ExecutionResult::RuntimeError("Only two numbers or two strings can be added");
                         }
                     } else {
                         return ExecutionResult::RuntimeError("Did not find 2 operands on the stack");
@@ -107,7 +230,9 @@ impl VirtualMachine {
                 Instruction::Subtract => {
                     if let Some((left, right)) = self.pop_two() {
                         if let Some(computed)= left.subtract(&right) {
-                            self.push(computed)
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
                         } else {
                             return ExecutionResult::RuntimeError("Only two numbers can be subtracted");
                         }
@@ -118,7 +243,9 @@ impl VirtualMachine {
                 Instruction::Multiply => {
                     if let Some((left, right)) = self.pop_two() {
                         if let Some(computed)= left.multiply(&right) {
-                            self.push(computed)
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
                         } else {
                             return ExecutionResult::RuntimeError("Only two numbers can be multiplied");
                         }
@@ -129,13 +256,136 @@ impl VirtualMachine {
                 Instruction::Divide => {
                     if let Some((left, right)) = self.pop_two() {
                         if let Some(computed)= left.divide(&right) {
-                            self.push(computed)
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
                         } else {
                             return ExecutionResult::RuntimeError("Only two numbers can be divided");
                         }
                     } else {
                         return ExecutionResult::RuntimeError("Did not find 2 operands on the stack");
                     }
+                },
+                Instruction::Stringify => {
+                    if let Some(value) = self.pop() {
+                        if let Err(failure) = self.push(value.stringify()) {
+                            return failure;
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 1 operand on the stack");
+                    }
+                },
+                Instruction::Not => {
+                    if let Some(value) = self.pop() {
+                        if let Err(failure) = self.push(value.not()) {
+                            return failure;
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 1 operand on the stack");
+                    }
+                },
+                Instruction::Equal => {
+                    if let Some((left, right)) = self.pop_two() {
+                        if let Err(failure) = self.push(left.equals(&right)) {
+                            return failure;
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 2 operands on the stack");
+                    }
+                },
+                Instruction::Greater => {
+                    if let Some((left, right)) = self.pop_two() {
+                        if let Some(computed) = left.greater(&right) {
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
+                        } else {
+                            return ExecutionResult::RuntimeError("Only two numbers can be compared");
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 2 operands on the stack");
+                    }
+                },
+                Instruction::Less => {
+                    if let Some((left, right)) = self.pop_two() {
+                        if let Some(computed) = left.less(&right) {
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
+                        } else {
+                            return ExecutionResult::RuntimeError("Only two numbers can be compared");
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 2 operands on the stack");
+                    }
+                },
+                Instruction::AddConstant(index) => {
+                    let right = match constant(chunk, index) {
+                        Ok(constant) => constant,
+                        Err(failure) => return failure
+                    };
+                    if let Some(left) = self.pop() {
+                        if let Some(computed) = left.add(&right) {
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
+                        } else {
+                            return ExecutionResult::RuntimeError("Only two numbers or two strings can be added");
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 1 operand on the stack");
+                    }
+                },
+                Instruction::SubtractConstant(index) => {
+                    let right = match constant(chunk, index) {
+                        Ok(constant) => constant,
+                        Err(failure) => return failure
+                    };
+                    if let Some(left) = self.pop() {
+                        if let Some(computed) = left.subtract(&right) {
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
+                        } else {
+                            return ExecutionResult::RuntimeError("Only two numbers can be subtracted");
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 1 operand on the stack");
+                    }
+                },
+                Instruction::MultiplyConstant(index) => {
+                    let right = match constant(chunk, index) {
+                        Ok(constant) => constant,
+                        Err(failure) => return failure
+                    };
+                    if let Some(left) = self.pop() {
+                        if let Some(computed) = left.multiply(&right) {
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
+                        } else {
+                            return ExecutionResult::RuntimeError("Only two numbers can be multiplied");
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 1 operand on the stack");
+                    }
+                },
+                Instruction::DivideConstant(index) => {
+                    let right = match constant(chunk, index) {
+                        Ok(constant) => constant,
+                        Err(failure) => return failure
+                    };
+                    if let Some(left) = self.pop() {
+                        if let Some(computed) = left.divide(&right) {
+                            if let Err(failure) = self.push(computed) {
+                                return failure;
+                            }
+                        } else {
+                            return ExecutionResult::RuntimeError("Only two numbers can be divided");
+                        }
+                    } else {
+                        return ExecutionResult::RuntimeError("Did not find 1 operand on the stack");
+                    }
                 }
             }
         }
@@ -147,6 +397,9 @@ impl VirtualMachine {
         self.stack.pop()
     }
     
+    // Underflow stays a runtime error rather than a debug assertion: verify
+    // checks instructions and constants but not stack depth, and chunks
+    // built through the public API are run without being verified at all.
     fn pop_two(&mut self) -> Option<(LoxValue, LoxValue)> {
         let right = self.pop();
         let left = self.pop();
@@ -158,56 +411,137 @@ impl VirtualMachine {
         }
     }
     
-    fn push(&mut self, value: LoxValue) {
-        self.stack.push(value)
+    fn push(&mut self, value: LoxValue) -> std::result::Result<(), ExecutionResult> {
+        if self.stack.len() >= self.stack_capacity {
+            return Err(ExecutionResult::RuntimeError("Stack overflow"));
+        }
+        
+        self.stack.push(value);
+        return Ok(());
     }
 }
 
-impl LoxValue {
-    
-    pub fn negate(&self) -> Option<LoxValue> {
-        match *self {
-            LoxValue::Number(value) => Some(LoxValue::Number(-value)),
-            _ => None
-        }
-    }
-    
-    pub fn add(&self, other: &LoxValue) -> Option<LoxValue> {
-        LoxValue::binary_numbers_action(self, other, &|left, right| left + right)
+// Chunks that were loaded have been verified, but one built any other way
+// may name a constant it does not have.
+fn constant(chunk: &Chunk, index: u8) -> Result<LoxValue, ExecutionResult> {
+    chunk.constant(index).cloned().ok_or(ExecutionResult::RuntimeError("Constant index out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vm_runs_within_stack_capacity() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Add),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::with_stack_capacity(2);
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::Ok);
     }
 
-    pub fn subtract(&self, other: &LoxValue) -> Option<LoxValue> {
-        LoxValue::binary_numbers_action(self, other, &|left, right| left - right)
+    #[test]
+    fn vm_reports_stack_overflow_as_runtime_error() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::with_stack_capacity(2);
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::RuntimeError("Stack overflow"));
     }
 
-    pub fn multiply(&self, other: &LoxValue) -> Option<LoxValue> {
-        LoxValue::binary_numbers_action(self, other, &|left, right| left * right)
+    #[test]
+    fn vm_executes_superinstructions() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::AddConstant(0)),
+            (1, Instruction::MultiplyConstant(0)),
+            (1, Instruction::SubtractConstant(0)),
+            (1, Instruction::DivideConstant(0)),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::create();
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::Ok);
+        assert_eq!(vm.returned_value().and_then(|v| v.as_number()), Some(1.0));
     }
 
-    pub fn divide(&self, other: &LoxValue) -> Option<LoxValue> {
-        LoxValue::binary_numbers_action(self, other, &|left, right| left / right)
+    #[test]
+    fn vm_recovers_after_runtime_error() {
+        let failing = Chunk::assemble(&[LoxValue::number(1.0), LoxValue::boolean(true)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(1)),
+            (1, Instruction::Negate),
+            (1, Instruction::Return)
+        ]);
+        let succeeding = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Add),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::with_stack_capacity(2);
+
+        assert_eq!(vm.run(&failing), ExecutionResult::RuntimeError("Only numbers can be negated"));
+        assert_eq!(vm.run(&succeeding), ExecutionResult::Ok);
+        assert_eq!(vm.run(&succeeding), ExecutionResult::Ok);
+        assert_eq!(vm.returned_value().and_then(|v| v.as_number()), Some(2.0));
     }
-    
-    fn binary_numbers_action(left: &LoxValue, right: &LoxValue, action: &Fn(f64, f64) -> f64)
-        -> Option<LoxValue> {
-        LoxValue::binary_numbers(left, right)
-            .and_then(|tuple| Some(LoxValue::Number(action(tuple.0, tuple.1))))
+
+    #[test]
+    fn vm_reports_missing_constants_as_runtime_error() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::AddConstant(1)),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::create();
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::RuntimeError("Constant index out of range"));
     }
 
-    fn binary_numbers(left: &LoxValue, right: &LoxValue) -> Option<(f64, f64)> {
-        if let (LoxValue::Number(left_value), LoxValue::Number(right_value)) = (*left, *right) {
-            Some((left_value, right_value))
-        } else {
-            None
-        }
+    #[test]
+    fn vm_reports_stack_underflow_as_runtime_error() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Add),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::create();
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::RuntimeError("Did not find 2 operands on the stack"));
     }
-}
 
-impl Debug for LoxValue {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        match self {
-            LoxValue::Number(foo) => write!(f, "{}", foo),
-            _ => write!(f, "")
-        }
+    #[test]
+    fn vm_compares_values() {
+        let chunk = Chunk::assemble(&[LoxValue::number(1.0), LoxValue::number(2.0)], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(1)),
+            (1, Instruction::Less),
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(1)),
+            (1, Instruction::Greater),
+            (1, Instruction::Not),
+            (1, Instruction::Equal),
+            (1, Instruction::Return)
+        ]);
+        let failing = Chunk::assemble(&[LoxValue::number(1.0), LoxValue::nil()], &[
+            (1, Instruction::Constant(0)),
+            (1, Instruction::Constant(1)),
+            (1, Instruction::Greater),
+            (1, Instruction::Return)
+        ]);
+        let mut vm = VirtualMachine::create();
+
+        assert_eq!(vm.run(&chunk), ExecutionResult::Ok);
+        assert_eq!(vm.returned_value().and_then(|v| v.as_boolean()), Some(true));
+        assert_eq!(vm.run(&failing), ExecutionResult::RuntimeError("Only two numbers can be compared"));
     }
-}
\ No newline at end of file
+}
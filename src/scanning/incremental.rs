@@ -0,0 +1,151 @@
+use std::borrow::Cow;
+use std::ops::Range;
+use super::{Scanner, Token, TokenType};
+
+// How many characters past the end of a token the scanner may look to
+// decide where the token ends, as when whitespace stops before a "\r\n".
+const LOOKAHEAD: usize = 2;
+
+// A change to a text: the bytes of the old text in `range` are replaced
+// by `replacement`.
+#[derive(Clone, Debug)]
+pub struct Edit<'e> {
+    range: Range<usize>,
+    replacement: &'e str
+}
+
+impl<'e> Edit<'e> {
+    pub fn create(range: Range<usize>, replacement: &'e str) -> Edit<'e> {
+        Edit { range, replacement }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let mut edited = String::with_capacity(text.len() - self.range.len() + self.replacement.len());
+        edited.push_str(&text[..self.range.start]);
+        edited.push_str(self.replacement);
+        edited.push_str(&text[self.range.end..]);
+        return edited;
+    }
+}
+
+impl<'a> Scanner<'a> {
+    // Brings tokens scanned from a text before an edit up to date with this
+    // scanner's source, the text after it, without scanning all of it
+    // again. The scanner must preserve the same trivia as the scanner that
+    // produced the tokens.
+    //
+    // Scanning restarts after the last token that ends clear of the edit
+    // outside any interpolated expression. Strings and comments are whole
+    // tokens, so that is never inside one either. It stops as soon as it is
+    // back in step with the old tokens after the edit, which are then only
+    // moved. Returns the updated tokens and the range of them that were
+    // scanned anew.
+    pub fn rescan(mut self, tokens: &[Token], edit: &Edit) -> (Vec<Token<'a>>, Range<usize>) {
+        let old_source = match tokens.first() {
+            Some(token) => token.source,
+            None => {
+                let updated: Vec<Token<'a>> = self.collect();
+                let length = updated.len();
+                return (updated, 0..length);
+            }
+        };
+
+        let before_edit = tokens.partition_point(|token| token.lexeme_end <= edit.range.start);
+        let restart = tokens[..before_edit].iter()
+            .rposition(|token| !token.interpolating && clear_of(old_source, token.lexeme_end, edit.range.start));
+
+        let mut updated: Vec<Token<'a>> = Vec::new();
+        if let Some(restart) = restart {
+            let token = &tokens[restart];
+            updated.extend(tokens[..=restart].iter().map(|token| token.moved(self.source, 0, 0)));
+            self.current = token.lexeme_end;
+            self.line_number = token.end_line();
+        }
+        let changed_start = updated.len();
+
+        // Offsets after the edit move by the difference in length, and line
+        // numbers by the difference in line breaks.
+        let delta = edit.replacement.len() as isize - edit.range.len() as isize;
+        let line_delta = count_lines(edit.replacement) as isize - count_lines(&old_source[edit.range.clone()]) as isize;
+        let edit_end = edit.range.start + edit.replacement.len();
+        let mut candidate = before_edit;
+
+        loop {
+            let token = self.scan_token();
+            let token_type = token.token_type;
+
+            // Past the edit, the scanner is in the same state as it was
+            // after an old token that ended at the same place outside any
+            // interpolated expression, and the rest of the text is the same.
+            if token.lexeme_end >= edit_end && !token.interpolating {
+                let old_end = (token.lexeme_end as isize - delta) as usize;
+                while candidate < tokens.len() && tokens[candidate].lexeme_end < old_end {
+                    candidate += 1;
+                }
+
+                let in_step = tokens.get(candidate)
+                    .is_some_and(|old| old.lexeme_end == old_end && !old.interpolating && old.token_type == token_type);
+                if in_step {
+                    updated.push(token);
+                    let changed_end = updated.len();
+                    updated.extend(tokens[candidate + 1..].iter().map(|token| token.moved(self.source, delta, line_delta)));
+                    return (updated, changed_start..changed_end);
+                }
+            }
+
+            updated.push(token);
+            if token_type == TokenType::EndOfFile {
+                let changed_end = updated.len();
+                return (updated, changed_start..changed_end);
+            }
+        }
+    }
+}
+
+impl<'a> Token<'a> {
+    // The same token in an edited copy of its source, where it starts
+    // `delta` bytes and `line_delta` lines further along.
+    fn moved<'b>(&self, source: &'b str, delta: isize, line_delta: isize) -> Token<'b> {
+        let shift = |offset: usize| (offset as isize + delta) as usize;
+
+        let value = self.value.as_ref().map(|value| match *value {
+            // A borrowed value is a slice of the old source, found at the
+            // same place in the new one.
+            Cow::Borrowed(text) => {
+                let start = shift(text.as_ptr() as usize - self.source.as_ptr() as usize);
+                Cow::Borrowed(&source[start..start + text.len()])
+            },
+            Cow::Owned(ref text) => Cow::Owned(text.clone())
+        });
+
+        Token {
+            token_type: self.token_type,
+            line_number: (self.line_number as isize + line_delta) as usize,
+            source,
+            lexeme_start: shift(self.lexeme_start),
+            lexeme_end: shift(self.lexeme_end),
+            value,
+            interpolating: self.interpolating
+        }
+    }
+
+    // Tokens report the line they end on, except newlines, which report
+    // the line they break, and unterminated strings, which run to the end
+    // of the source and so are never restarted from.
+    fn end_line(&self) -> usize {
+        if self.token_type == TokenType::Newline {
+            return self.line_number + 1;
+        }
+        return self.line_number;
+    }
+}
+
+// Whether the scanner could not have looked at `edit_start` while
+// scanning a token that ends at `token_end`.
+fn clear_of(source: &str, token_end: usize, edit_start: usize) -> bool {
+    source[token_end..edit_start].chars().nth(LOOKAHEAD - 1).is_some()
+}
+
+fn count_lines(text: &str) -> usize {
+    text.bytes().filter(|&b| b == b'\n').count()
+}
@@ -1,21 +1,48 @@
-use std::str::Chars;
-mod seq;
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use unicode_ident::{is_xid_continue, is_xid_start};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+pub mod incremental;
+#[cfg(test)]
 mod tests;
 
 pub struct Scanner<'a> {
+    source: &'a str,
     line_number: usize,
+    // Byte offsets into the source of the lexeme being scanned and of the
+    // next character.
     lexeme_start: usize,
-    current_character: usize,
-    consumed_characters: Vec<char>,
-    source: seq::CharacterSequence<'a, char>
+    current: usize,
+    // Emit `//` comments as Comment tokens instead of skipping them, for
+    // tools such as the formatter that must not lose them.
+    preserve_comments: bool,
+    // Emit whitespace and newlines as tokens too, so that the lexemes of
+    // all the tokens put together are the source text exactly.
+    preserve_trivia: bool,
+    // The brace depth within each interpolated expression being scanned,
+    // innermost last. A closing brace at depth zero resumes the string.
+    interpolations: Vec<usize>,
+    // Set once iteration has yielded the EndOfFile token.
+    finished: bool
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Token {
+// Tokens borrow their lexemes from the source instead of copying them.
+#[derive(Clone)]
+pub struct Token<'a> {
     token_type: TokenType,
     line_number: usize,
+    source: &'a str,
     lexeme_start: usize,
-    lexeme: String
+    lexeme_end: usize,
+    // The decoded contents of a string literal, with the quotes removed
+    // and escape sequences replaced, or the name an identifier stands for.
+    // Only literals with escapes and names that are not already normalized
+    // need a copy of their own.
+    value: Option<Cow<'a, str>>,
+    // Whether an interpolated expression was still open after this token,
+    // in which case scanning cannot be restarted from its end.
+    interpolating: bool
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -32,59 +59,171 @@ pub enum TokenType {
     
     Identifier, String, Number,
     
+    // The fragments of a string with interpolated expressions between
+    // them, as in "a ${b} c ${d} e": `"a ${`, `} c ${` and `} e"`.
+    InterpolationStart, InterpolationMiddle, InterpolationEnd,
+    
     And, Class, Else, False,
     Fun, For, If, Nil, Or,
     Print, Return, Super, This,
     True, Var, While,
     
+    Comment, DocComment, Whitespace, Newline,
     Error(&'static str),
     EndOfFile
 }
 
+impl TokenType {
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, TokenType::Comment | TokenType::DocComment | TokenType::Whitespace | TokenType::Newline)
+    }
+}
+
+impl<'a> Token<'a> {
+    pub fn token_type(&self) -> TokenType {
+        self.token_type
+    }
+    
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+    
+    // The byte offset of the lexeme in the source.
+    pub fn lexeme_start(&self) -> usize {
+        self.lexeme_start
+    }
+    
+    pub fn lexeme_end(&self) -> usize {
+        self.lexeme_end
+    }
+    
+    pub fn lexeme(&self) -> &'a str {
+        &self.source[self.lexeme_start..self.lexeme_end]
+    }
+    
+    // The column of the lexeme's first character, counting characters from
+    // one. Few tokens ever need one, so it is worked out on demand.
+    pub fn column(&self) -> usize {
+        self.source[..self.lexeme_start].chars().rev().take_while(|&c| c != '\n').count() + 1
+    }
+    
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+}
+
+impl<'a> PartialEq for Token<'a> {
+    fn eq(&self, other: &Token<'a>) -> bool {
+        self.token_type == other.token_type
+            && self.line_number == other.line_number
+            && self.lexeme_start == other.lexeme_start
+            && self.lexeme() == other.lexeme()
+            && self.value == other.value
+    }
+}
+
+impl<'a> Eq for Token<'a> {}
+
+impl<'a> Debug for Token<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("token_type", &self.token_type)
+            .field("line_number", &self.line_number)
+            .field("lexeme_start", &self.lexeme_start)
+            .field("lexeme", &self.lexeme())
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
 impl<'a> Scanner<'a> {
-    pub fn create(input: &'a mut Chars) -> Scanner<'a> {
+    pub fn create(source: &'a str) -> Scanner<'a> {
         Scanner {
+            source,
             line_number: 1,
             lexeme_start: 0,
-            current_character: 0,
-            consumed_characters: Vec::new(),
-            source: seq::CharacterSequence::new(input)
+            current: 0,
+            preserve_comments: false,
+            preserve_trivia: false,
+            interpolations: Vec::new(),
+            finished: false
         }
     }
     
-    pub fn next(&mut self) -> Token {
-        self.skip_whitespace();
+    pub fn preserve_comments(&mut self) {
+        self.preserve_comments = true;
+    }
+    
+    pub fn preserve_trivia(&mut self) {
+        self.preserve_comments = true;
+        self.preserve_trivia = true;
+    }
+    
+    // Scans the next token. Once the source is exhausted every call returns
+    // EndOfFile, which suits a parser that looks ahead past the end.
+    pub fn scan_token(&mut self) -> Token<'a> {
+        if !self.preserve_trivia {
+            if let Some(token) = self.skip_whitespace() {
+                return token;
+            }
+        }
         
-        self.lexeme_start = self.current_character;
-        self.consumed_characters.clear();
+        self.lexeme_start = self.current;
         
-        let next_character = self.consume_and_append();
+        let next_character = self.consume();
         
-        if let None = next_character {
+        if next_character.is_none() {
             return self.create_token(TokenType::EndOfFile);
         }
         
         let next_character = next_character.unwrap();
         
+        if self.preserve_trivia {
+            if let Some(token) = self.create_trivia_token(next_character) {
+                return token;
+            }
+        }
+        
         if '"' == next_character {
-            return self.create_string_token();
+            return self.create_string_token(false);
         }
         
-        if next_character.is_numeric() {
-            return self.create_number_token();
+        if next_character.is_ascii_digit() {
+            return self.create_number_token(next_character);
+        }
+        
+        if is_identifier_start(next_character) {
+            return self.create_identifier_token();
         }
         
         let token_type = match next_character {
             '(' => TokenType::LeftParen,
             ')' => TokenType::RightParen,
-            '{' => TokenType::LeftBrace,
-            '}' => TokenType::RightBrace,
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                TokenType::LeftBrace
+            },
+            '}' => {
+                if self.interpolations.last() == Some(&0) {
+                    self.interpolations.pop();
+                    return self.create_string_token(true);
+                }
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth -= 1;
+                }
+                TokenType::RightBrace
+            },
             ',' => TokenType::Comma,
             '.' => TokenType::Dot,
             '-' => TokenType::Minus,
             '+' => TokenType::Plus, 
             ';' => TokenType::SemiColon, 
-            '/' => TokenType::Slash,
+            '/' => match self.peek_next() {
+                Some('/') | Some('*') => return self.create_comment_token(),
+                _ => TokenType::Slash
+            },
             '*' => TokenType::Star,
             '!' => match self.match_next('=') {
                 Some(_) => TokenType::BangEqual,
@@ -102,75 +241,337 @@ impl<'a> Scanner<'a> {
                 Some(_) => TokenType::LessEqual,
                 None => TokenType::Less
             },
-            _ => TokenType::Error("Unexpected character")
+            _ => {
+                // A run of characters that cannot start a token is a single
+                // error, and scanning resumes at the next one that can.
+                while self.peek_next().is_some_and(|c| !starts_token(c)) {
+                    self.consume();
+                }
+                TokenType::Error("Unexpected character")
+            }
         };
         
         return self.create_token(token_type);
     }
     
-    fn create_token(&self, token_type: TokenType) -> Token {
+    fn create_token(&self, token_type: TokenType) -> Token<'a> {
         Token {
             token_type,
             line_number: self.line_number,
+            source: self.source,
             lexeme_start: self.lexeme_start,
-            lexeme: self.consumed_characters.iter().collect()
+            lexeme_end: self.current,
+            value: None,
+            interpolating: !self.interpolations.is_empty()
         }
     }
     
-    fn create_string_token(&mut self) -> Token {
-        loop {
-            match self.source.peek_next() {
-                Some('"') => { return self.create_token(TokenType::String); },
-                Some('\n') => { self.line_number += 1; },
-                Some(_) => { self.consume_and_append(); },
-                None => { self.create_token(TokenType::Error("Expected terminating '\"' for string")); }
+    // Identifiers follow Unicode's UAX #31: a letter or underscore followed
+    // by letters, digits, combining marks and connectors.
+    fn create_identifier_token(&mut self) -> Token<'a> {
+        while self.peek_next().is_some_and(is_xid_continue) {
+            self.consume();
+        }
+        
+        let lexeme = &self.source[self.lexeme_start..self.current];
+        let token_type = keyword(lexeme).unwrap_or(TokenType::Identifier);
+        let mut token = self.create_token(token_type);
+        
+        // Names are compared in Normalization Form C, so that a letter typed
+        // as a base and a combining accent names the same thing as the
+        // precomposed letter it looks identical to.
+        if token_type == TokenType::Identifier {
+            token.value = Some(if is_nfc(lexeme) {
+                Cow::Borrowed(lexeme)
+            } else {
+                Cow::Owned(lexeme.nfc().collect())
+            });
+        }
+        
+        return token;
+    }
+    
+    // Scans a string literal, or the rest of one after an interpolated
+    // expression when `continued` is set, up to its end or the next `${`.
+    fn create_string_token(&mut self, continued: bool) -> Token<'a> {
+        let source = self.source;
+        let content_start = self.current;
+        let start_line = self.line_number;
+        // The contents are borrowed from the source until an escape needs
+        // decoding.
+        let mut decoded: Option<String> = None;
+        // The rest of the literal is still consumed after a bad escape so
+        // that its contents are not scanned as code.
+        let mut error = None;
+        
+        let (token_type, content_end) = loop {
+            let position = self.current;
+            
+            match self.consume() {
+                Some('"') if continued => break (TokenType::InterpolationEnd, position),
+                Some('"') => break (TokenType::String, position),
+                Some('$') if self.peek_next() == Some('{') => {
+                    self.consume();
+                    self.interpolations.push(0);
+                    
+                    if continued {
+                        break (TokenType::InterpolationMiddle, position);
+                    }
+                    break (TokenType::InterpolationStart, position);
+                },
+                Some('\\') => {
+                    let decoded = decoded.get_or_insert_with(|| source[content_start..position].to_string());
+                    match self.scan_escape() {
+                        Ok(c) => decoded.push(c),
+                        Err(message) => { error = error.or(Some(message)); }
+                    }
+                },
+                Some(c) => {
+                    if c == '\n' {
+                        self.line_number += 1;
+                    }
+                    if let Some(ref mut decoded) = decoded {
+                        decoded.push(c);
+                    }
+                },
+                None => {
+                    // Reported where the string starts rather than at the
+                    // end of the file, which is where scanning gave up.
+                    let mut token = self.create_token(TokenType::Error("Unterminated string"));
+                    token.line_number = start_line;
+                    return token;
+                }
             }
+        };
+        
+        if let Some(message) = error {
+            return self.create_token(TokenType::Error(message));
         }
-
         
+        let mut token = self.create_token(token_type);
+        token.value = Some(match decoded {
+            Some(decoded) => Cow::Owned(decoded),
+            None => Cow::Borrowed(&source[content_start..content_end])
+        });
+        return token;
     }
     
-    fn create_number_token(&mut self) -> Token {
-        loop {
-            match self.source.peek_next().filter(|c| c.is_numeric()) {
-                Some(_) => self.consume_and_append(),
-                None => break
-            };
+    fn scan_escape(&mut self) -> Result<char, &'static str> {
+        // A newline or the end of input is left for the string loop.
+        let escaped = match self.peek_next() {
+            Some('\n') | None => return Err("Invalid escape sequence"),
+            Some(c) => c
+        };
+        
+        self.consume();
+        
+        match escaped {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            '$' => Ok('$'),
+            'u' => self.scan_unicode_escape(),
+            _ => Err("Invalid escape sequence")
         }
+    }
+    
+    fn scan_unicode_escape(&mut self) -> Result<char, &'static str> {
+        const INVALID: &str = "Invalid unicode escape sequence";
         
-        if self.source.peek_next().filter(|c| *c == '.').is_some() {
-            self.consume_and_append();
-
-            loop {
-                match self.source.peek_next().filter(|c| c.is_numeric()) {
-                    Some(_) => self.consume_and_append(),
-                    None => break
-                };
-            };
+        if self.match_next('{').is_none() {
+            return Err(INVALID);
+        }
+        
+        let mut digits = String::new();
+        while let Some(c) = self.peek_next().filter(|c| c.is_ascii_hexdigit()) {
+            self.consume();
+            digits.push(c);
+        }
+        
+        if self.match_next('}').is_none() || digits.is_empty() || digits.len() > 6 {
+            return Err(INVALID);
+        }
+        
+        // Surrogates and values past the last code point are rejected.
+        u32::from_str_radix(&digits, 16).ok()
+            .and_then(std::char::from_u32)
+            .ok_or(INVALID)
+    }
+    
+    fn create_trivia_token(&mut self, first_character: char) -> Option<Token<'a>> {
+        if first_character == '\n' || (first_character == '\r' && self.match_next('\n').is_some()) {
+            let token = self.create_token(TokenType::Newline);
+            self.line_number += 1;
+            return Some(token);
+        }
+        
+        if !is_blank(first_character) {
+            return None;
+        }
+        
+        // A carriage return that starts a line ending belongs to the newline.
+        while let Some(c) = self.peek_next() {
+            if !is_blank(c) || (c == '\r' && self.peek_ahead() == Some('\n')) {
+                break;
+            }
+            self.consume();
+        }
+        
+        return Some(self.create_token(TokenType::Whitespace));
+    }
+    
+    // Scans a `//` line comment, a `///` doc comment or a `/* */` block
+    // comment, whose opening slash has already been consumed.
+    fn create_comment_token(&mut self) -> Token<'a> {
+        if self.match_next('*').is_some() {
+            if !self.scan_block_comment() {
+                return self.create_token(TokenType::Error("Unterminated block comment"));
+            }
+            return self.create_token(TokenType::Comment);
+        }
+        
+        self.consume();
+        // Exactly three slashes, so that //// rulers stay plain comments.
+        let is_doc = self.peek_next() == Some('/') && self.peek_ahead() != Some('/');
+        
+        // The newline is left for the caller so it is counted.
+        while self.peek_next().filter(|c| *c != '\n').is_some() {
+            self.consume();
+        }
+        
+        if is_doc {
+            return self.create_token(TokenType::DocComment);
+        }
+        return self.create_token(TokenType::Comment);
+    }
+    
+    // Consumes a block comment up to the `*/` matching its opening, with
+    // nested comments balanced. Returns false at the end of input.
+    fn scan_block_comment(&mut self) -> bool {
+        let mut depth = 1;
+        
+        while depth > 0 {
+            match self.consume() {
+                Some('/') if self.peek_next() == Some('*') => {
+                    self.consume();
+                    depth += 1;
+                },
+                Some('*') if self.peek_next() == Some('/') => {
+                    self.consume();
+                    depth -= 1;
+                },
+                Some('\n') => self.line_number += 1,
+                Some(_) => {},
+                None => return false
+            }
+        }
+        
+        return true;
+    }
+    
+    // Scans decimal numbers with an optional fraction and exponent, and
+    // 0x and 0b integers, all allowing single `_` separators between digits.
+    fn create_number_token(&mut self, first_digit: char) -> Token<'a> {
+        let prefix = match (first_digit, self.peek_next()) {
+            ('0', Some('x')) | ('0', Some('X')) => Some((16, "Expect hexadecimal digits after '0x'")),
+            ('0', Some('b')) | ('0', Some('B')) => Some((2, "Expect binary digits after '0b'")),
+            _ => None
+        };
+        
+        let scanned = match prefix {
+            Some((radix, message)) => {
+                self.consume();
+                self.scan_digits(radix).and_then(|count| if count == 0 { Err(message) } else { Ok(()) })
+            },
+            None => self.scan_decimal()
+        };
+        
+        if let Err(message) = scanned {
+            return self.create_token(TokenType::Error(message));
+        }
+        
+        // Letters or other digits straight after a literal, as in 12px,
+        // 0b102 or a non-ASCII digit, make the whole run malformed.
+        if self.peek_next().filter(|c| c.is_alphanumeric() || *c == '_').is_some() {
+            while self.peek_next().filter(|c| c.is_alphanumeric() || *c == '_').is_some() {
+                self.consume();
+            }
+            return self.create_token(TokenType::Error("Unexpected character in number literal"));
         }
         
         return self.create_token(TokenType::Number);
     }
     
-    fn skip_whitespace(&mut self) {
+    fn scan_decimal(&mut self) -> Result<(), &'static str> {
+        self.scan_digits(10)?;
+        
+        if self.match_next('.').is_some() {
+            if self.peek_next().filter(|c| c.is_ascii_digit()).is_none() {
+                return Err("Expect digits after '.'");
+            }
+            self.scan_digits(10)?;
+        }
+        
+        if self.match_next('e').or_else(|| self.match_next('E')).is_some() {
+            self.match_next('+').or_else(|| self.match_next('-'));
+            
+            if self.peek_next().filter(|c| c.is_ascii_digit()).is_none() {
+                return Err("Expect digits in exponent");
+            }
+            self.scan_digits(10)?;
+        }
+        
+        return Ok(());
+    }
+    
+    // Consumes digits of the radix, returning how many there were. A
+    // separator must sit between two digits.
+    fn scan_digits(&mut self, radix: u32) -> Result<usize, &'static str> {
+        let mut count = 0;
+        
+        loop {
+            match self.peek_next() {
+                Some(c) if c.is_digit(radix) => {
+                    self.consume();
+                    count += 1;
+                },
+                Some('_') => {
+                    let after_digit = self.previous_character().filter(|c| c.is_digit(radix)).is_some();
+                    let before_digit = self.peek_ahead().filter(|c| c.is_digit(radix)).is_some();
+                    self.consume();
+                    
+                    if !after_digit || !before_digit {
+                        return Err("Misplaced digit separator");
+                    }
+                },
+                _ => return Ok(count)
+            }
+        }
+    }
+    
+    // Skips whitespace and, unless they are preserved, comments. Doc
+    // comments and unterminated block comments still have to be reported,
+    // so those come back as tokens.
+    fn skip_whitespace(&mut self) -> Option<Token<'a>> {
         loop {
-            match self.source.peek_next() {
-                Some(' ') => {self.consume_and_discard();},
-                Some('\r') => {self.consume_and_discard();},
-                Some('\t') => {self.consume_and_discard();},
+            match self.peek_next() {
+                Some(' ') => {self.consume();},
+                Some('\r') => {self.consume();},
+                Some('\t') => {self.consume();},
                 Some('\n') => {
                     self.line_number += 1;
-                    self.consume_and_discard();
+                    self.consume();
                 },
                 Some('/') => {
-                    match self.source.peek_ahead() {
-                        Some('/') => {
-                            self.consume_and_discard();
-                            self.consume_and_discard();
-                            while let Some(c) = self.consume_and_discard() {
-                                if c == '\n' {
-                                    break;
-                                }
+                    match self.peek_ahead() {
+                        Some('/') | Some('*') if !self.preserve_comments => {
+                            self.lexeme_start = self.current;
+                            self.consume();
+                            
+                            let comment = self.create_comment_token();
+                            if comment.token_type != TokenType::Comment {
+                                return Some(comment);
                             }
                         }
                         _ => { break; }
@@ -180,36 +581,93 @@ impl<'a> Scanner<'a> {
                 None => break
             };
         }
+        
+        return None;
     }
     
     fn match_next(&mut self, expected: char) -> Option<char> {
-        if let Some(c) = self.source.peek_next() {
+        if let Some(c) = self.peek_next() {
             if c == expected {
-                return self.consume_and_append();
+                return self.consume();
             }
         }
         
         return None;
     }
     
-    fn consume_and_append(&mut self) -> Option<char> {
-        let character = self.source.next();
-        
-        if let Some(c) = character {
-            self.current_character += 1;
-            self.consumed_characters.push(c);
+    fn consume(&mut self) -> Option<char> {
+        let character = self.peek_next()?;
+        self.current += character.len_utf8();
+        return Some(character);
+    }
+    
+    fn peek_next(&self) -> Option<char> {
+        self.source[self.current..].chars().next()
+    }
+    
+    fn peek_ahead(&self) -> Option<char> {
+        let mut characters = self.source[self.current..].chars();
+        characters.next();
+        return characters.next();
+    }
+    
+    fn previous_character(&self) -> Option<char> {
+        self.source[..self.current].chars().next_back()
+    }
+}
+
+// Iterating yields every token up to and including EndOfFile, then stops.
+impl<'a> Iterator for Scanner<'a> {
+    type Item = Token<'a>;
+    
+    fn next(&mut self) -> Option<Token<'a>> {
+        if self.finished {
+            return None;
         }
         
-        return character;
+        let token = self.scan_token();
+        self.finished = token.token_type == TokenType::EndOfFile;
+        return Some(token);
     }
+}
+
+// Scans the whole source, ending with the EndOfFile token.
+pub fn tokenize<'a>(source: &'a str) -> Vec<Token<'a>> {
+    Scanner::create(source).collect()
+}
+
+fn keyword(lexeme: &str) -> Option<TokenType> {
+    let token_type = match lexeme {
+        "and" => TokenType::And,
+        "class" => TokenType::Class,
+        "else" => TokenType::Else,
+        "false" => TokenType::False,
+        "for" => TokenType::For,
+        "fun" => TokenType::Fun,
+        "if" => TokenType::If,
+        "nil" => TokenType::Nil,
+        "or" => TokenType::Or,
+        "print" => TokenType::Print,
+        "return" => TokenType::Return,
+        "super" => TokenType::Super,
+        "this" => TokenType::This,
+        "true" => TokenType::True,
+        "var" => TokenType::Var,
+        "while" => TokenType::While,
+        _ => return None
+    };
     
-    fn consume_and_discard(&mut self) -> Option<char> {
-        let character = self.source.next();
+    return Some(token_type);
+}
 
-        if let Some(_) = character {
-            self.current_character += 1;
-        }
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || is_xid_start(c)
+}
 
-        return character;
-    }
+fn starts_token(c: char) -> bool {
+    is_identifier_start(c) || is_blank(c) || c == '\n' || c.is_ascii_digit() || "(){},.-+;/*!=<>\"".contains(c)
+}
+
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r'
 }
@@ -1,5 +1,7 @@
 use super::*;
+use super::incremental::Edit;
 use std::fmt::Debug;
+use proptest::prelude::*;
 
 
 #[test]
@@ -69,6 +71,55 @@ fn scanner_ignore_comments_at_file_end() {
     test_scanner(corpus, &expected);
 }
 
+#[test]
+fn scanner_preserves_comments_on_request() {
+    let mut scanner = Scanner::create("+ // a comment\n// another");
+    scanner.preserve_comments();
+    
+    let plus = scanner.scan_token();
+    let comment = scanner.scan_token();
+    let another = scanner.scan_token();
+    
+    assert_eq!(plus.token_type, TokenType::Plus);
+    assert_eq!(comment.token_type, TokenType::Comment);
+    assert_eq!(comment.lexeme(), "// a comment");
+    assert_eq!(comment.line_number, 1);
+    assert_eq!(another.lexeme(), "// another");
+    assert_eq!(another.line_number, 2);
+    assert_eq!(scanner.scan_token().token_type, TokenType::EndOfFile);
+}
+
+#[test]
+fn scanner_emits_trivia_on_request() {
+    let tokens = tokenize_trivia("1 \t+\r\n// note\n");
+    let kinds: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+    
+    assert_eq!(kinds, vec![
+        TokenType::Number,
+        TokenType::Whitespace,
+        TokenType::Plus,
+        TokenType::Newline,
+        TokenType::Comment,
+        TokenType::Newline,
+        TokenType::EndOfFile
+    ]);
+    assert_eq!(tokens[1].lexeme(), " \t");
+    assert_eq!(tokens[3].lexeme(), "\r\n");
+    assert_eq!(tokens[3].line_number, 1);
+    assert_eq!(tokens[4].line_number, 2);
+    assert!(tokens[4].token_type.is_trivia());
+}
+
+#[test]
+fn scanner_trivia_mode_is_lossless() {
+    let corpus = "  (1.5+ 2)\t/* a\n/* b */ */// sum \u{e9}\r\n\r\n\r %\u{3c0}-3 *\n\n  4//\n/ \"two\\\\\nlines\" \r";
+    let tokens = tokenize_trivia(corpus);
+    let lexemes: String = tokens.iter().map(|t| t.lexeme()).collect();
+    
+    assert_eq!(lexemes, corpus);
+    assert_eq!(tokens.last().unwrap().line_number, 8);
+}
+
 #[test]
 fn scanner_finds_string_literals() {
     let corpus = "+.\"literal string // string literal\"))";
@@ -87,37 +138,370 @@ fn scanner_finds_string_literals() {
     
     assert_eq!(string_token.line_number, 1);
     assert_eq!(string_token.lexeme_start, 2);
-    assert_eq!(string_token.lexeme, "\"literal string // string literal\"".to_string());
+    assert_eq!(string_token.lexeme(), "\"literal string // string literal\"".to_string());
 }
 
-fn test_scanner(corpus: &str, expected_tokens: &[TokenType]) {
-    let actual_tokens: Vec<TokenType> = tokenize(corpus)
-        .iter()
-        .map(|t| t.token_type)
+#[test]
+fn scanner_decodes_string_escapes() {
+    let tokens = tokenize(r#""tab\there \"quoted\" back\\slash\nnew \u{e9}\u{1F600}""#);
+    
+    assert_eq!(tokens[0].token_type, TokenType::String);
+    assert_eq!(tokens[0].value(), Some("tab\there \"quoted\" back\\slash\nnew \u{e9}\u{1F600}"));
+    assert_eq!(tokens[0].lexeme(), r#""tab\there \"quoted\" back\\slash\nnew \u{e9}\u{1F600}""#);
+    assert_eq!(tokens[1].token_type, TokenType::EndOfFile);
+}
+
+#[test]
+fn scanner_counts_lines_in_multi_line_strings() {
+    let tokens = tokenize("\"one\ntwo\n\" +");
+    
+    assert_eq!(tokens[0].value(), Some("one\ntwo\n"));
+    assert_eq!(tokens[0].line_number, 3);
+    assert_eq!(tokens[1].token_type, TokenType::Plus);
+    assert_eq!(tokens[1].line_number, 3);
+}
+
+#[test]
+fn scanner_iterates_up_to_end_of_file() {
+    let mut scanner = Scanner::create("+ -");
+    let token_types: Vec<TokenType> = scanner.by_ref().map(|token| token.token_type()).collect();
+    
+    assert_eq!(token_types, vec![TokenType::Plus, TokenType::Minus, TokenType::EndOfFile]);
+    assert_eq!(scanner.next(), None);
+    assert_eq!(scanner.scan_token().token_type, TokenType::EndOfFile);
+    assert_eq!(tokenize("").len(), 1);
+}
+
+#[test]
+fn scanner_reports_unterminated_strings() {
+    let tokens = tokenize("+ \"never\nclosed");
+    
+    assert_eq!(tokens[1].token_type, TokenType::Error("Unterminated string"));
+    assert_eq!(tokens[1].lexeme_start, 2);
+    assert_eq!(tokens[1].line_number, 1);
+    assert_eq!(tokens[2].line_number, 2);
+    
+    let mut scanner = Scanner::create("\"");
+    assert_eq!(scanner.scan_token().token_type, TokenType::Error("Unterminated string"));
+    assert_eq!(scanner.scan_token().token_type, TokenType::EndOfFile);
+}
+
+#[test]
+fn scanner_reports_invalid_escapes_and_resumes_after_the_string() {
+    let cases = [
+        (r#""\q" +"#, "Invalid escape sequence"),
+        (r#""\u41" +"#, "Invalid unicode escape sequence"),
+        (r#""\u{}" +"#, "Invalid unicode escape sequence"),
+        (r#""\u{D800}" +"#, "Invalid unicode escape sequence"),
+        (r#""\u{1234567}" +"#, "Invalid unicode escape sequence"),
+        (r#""\u{4g}" +"#, "Invalid unicode escape sequence")
+    ];
+    
+    for &(corpus, message) in &cases {
+        let mut scanner = Scanner::create(corpus);
+        let error = scanner.scan_token();
+        
+        assert_eq!(error.token_type, TokenType::Error(message), "{}", corpus);
+        assert_eq!(error.lexeme(), corpus.trim_end_matches(" +"));
+        assert_eq!(scanner.scan_token().token_type, TokenType::Plus, "{}", corpus);
+    }
+}
+
+#[test]
+fn scanner_splits_interpolated_strings_into_fragments() {
+    let tokens = tokenize(r#""Hello ${"a ${1}" + {}}, \${not} ${2}""#);
+    let kinds: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+    
+    assert_eq!(kinds, vec![
+        TokenType::InterpolationStart,
+        TokenType::InterpolationStart,
+        TokenType::Number,
+        TokenType::InterpolationEnd,
+        TokenType::Plus,
+        TokenType::LeftBrace,
+        TokenType::RightBrace,
+        TokenType::InterpolationMiddle,
+        TokenType::Number,
+        TokenType::InterpolationEnd,
+        TokenType::EndOfFile
+    ]);
+    assert_eq!(tokens[0].lexeme(), "\"Hello ${");
+    assert_eq!(tokens[0].value(), Some("Hello "));
+    assert_eq!(tokens[3].lexeme(), "}\"");
+    assert_eq!(tokens[3].value(), Some(""));
+    assert_eq!(tokens[7].lexeme(), r#"}, \${not} ${"#);
+    assert_eq!(tokens[7].value(), Some(", ${not} "));
+}
+
+#[test]
+fn scanner_recognizes_extended_number_literals() {
+    for corpus in &["0", "42", "3.25", "1_000_000", "0x1F", "0Xab_CD", "0b1010", "0B1_0", "1e9", "2.5E-3", "7e+1_0"] {
+        let tokens = tokenize(corpus);
+        
+        assert_eq!(tokens[0].token_type, TokenType::Number, "{}", corpus);
+        assert_eq!(tokens[0].lexeme(), *corpus);
+        assert_eq!(tokens[1].token_type, TokenType::EndOfFile, "{}", corpus);
+    }
+}
+
+#[test]
+fn scanner_reports_malformed_number_literals() {
+    let cases = [
+        ("1. +", "1.", "Expect digits after '.'"),
+        ("1.x", "1.", "Expect digits after '.'"),
+        ("0x +", "0x", "Expect hexadecimal digits after '0x'"),
+        ("0b +", "0b", "Expect binary digits after '0b'"),
+        ("1e +", "1e", "Expect digits in exponent"),
+        ("1e- +", "1e-", "Expect digits in exponent"),
+        ("1__0 +", "1_", "Misplaced digit separator"),
+        ("10_ +", "10_", "Misplaced digit separator"),
+        ("0x_1 +", "0x_", "Misplaced digit separator"),
+        ("0b102 +", "0b102", "Unexpected character in number literal"),
+        ("12px +", "12px", "Unexpected character in number literal"),
+        ("1\u{663} +", "1\u{663}", "Unexpected character in number literal")
+    ];
+    
+    for &(corpus, lexeme, message) in &cases {
+        let tokens = tokenize(corpus);
+        
+        assert_eq!(tokens[0].token_type, TokenType::Error(message), "{}", corpus);
+        assert_eq!(tokens[0].lexeme(), lexeme, "{}", corpus);
+        assert_eq!(tokens[0].lexeme_start, 0);
+    }
+}
+
+#[test]
+fn scanner_only_accepts_ascii_digits() {
+    let tokens = tokenize("\u{663}");
+    
+    assert_eq!(tokens[0].token_type, TokenType::Error("Unexpected character"));
+}
+
+#[test]
+fn scanner_reports_each_run_of_unexpected_characters_once() {
+    let tokens = tokenize("1 @#% + \u{a7}\u{2603}\n~");
+    let errors: Vec<(&str, usize, usize)> = tokens.iter()
+        .filter(|t| t.token_type == TokenType::Error("Unexpected character"))
+        .map(|t| (t.lexeme(), t.line_number, t.column()))
         .collect();
     
-    assert_slice_eq(actual_tokens.as_slice(), &expected_tokens);
+    assert_eq!(errors, vec![("@#%", 1, 3), ("\u{a7}\u{2603}", 1, 9), ("~", 2, 1)]);
+    assert_eq!(tokens[2].token_type, TokenType::Plus);
+    assert_eq!(tokens.last().unwrap().token_type, TokenType::EndOfFile);
+}
+
+#[test]
+fn scanner_scans_keywords_and_unicode_identifiers() {
+    let tokens = tokenize("var _x1 = caf\u{e9} or \u{3c0}\u{2080} while\u{2d9}");
+    let kinds: Vec<(TokenType, &str)> = tokens.iter().map(|t| (t.token_type, t.lexeme())).collect();
+    
+    assert_eq!(kinds, vec![
+        (TokenType::Var, "var"),
+        (TokenType::Identifier, "_x1"),
+        (TokenType::Equal, "="),
+        (TokenType::Identifier, "caf\u{e9}"),
+        (TokenType::Or, "or"),
+        (TokenType::Identifier, "\u{3c0}"),
+        (TokenType::Error("Unexpected character"), "\u{2080}"),
+        (TokenType::While, "while"),
+        (TokenType::Error("Unexpected character"), "\u{2d9}"),
+        (TokenType::EndOfFile, "")
+    ]);
+    assert_eq!(tokens[1].value(), Some("_x1"));
+    assert_eq!(tokens[4].value(), None);
+}
+
+#[test]
+fn scanner_normalizes_identifiers_to_nfc() {
+    let precomposed = tokenize("caf\u{e9}");
+    let decomposed = tokenize("cafe\u{301}");
+    
+    assert_eq!(decomposed[0].token_type, TokenType::Identifier);
+    assert_eq!(decomposed[0].lexeme(), "cafe\u{301}");
+    assert_eq!(decomposed[0].value(), precomposed[0].value());
+    assert!(matches!(precomposed[0].value, Some(Cow::Borrowed(_))));
+}
+
+#[test]
+fn scanner_skips_nested_block_comments() {
+    let tokens = tokenize("+ /* outer /* inner\n */ still // outer\n*/ -\n// line\n.");
+    let kinds: Vec<(TokenType, usize)> = tokens.iter().map(|t| (t.token_type, t.line_number)).collect();
+    
+    assert_eq!(kinds, vec![
+        (TokenType::Plus, 1),
+        (TokenType::Minus, 3),
+        (TokenType::Dot, 5),
+        (TokenType::EndOfFile, 5)
+    ]);
+}
+
+#[test]
+fn scanner_reports_unterminated_block_comments() {
+    let tokens = tokenize("+\n/* open /* nested */\n");
+    
+    assert_eq!(tokens[1].token_type, TokenType::Error("Unterminated block comment"));
+    assert_eq!(tokens[1].lexeme_start, 2);
+    assert_eq!(tokens[1].lexeme(), "/* open /* nested */\n");
+}
+
+#[test]
+fn scanner_emits_doc_comments() {
+    let tokens = tokenize("/// Adds.\n//// ruler\n+ ///\n");
+    
+    assert_eq!(tokens[0].token_type, TokenType::DocComment);
+    assert_eq!(tokens[0].lexeme(), "/// Adds.");
+    assert_eq!(tokens[1].token_type, TokenType::Plus);
+    assert_eq!(tokens[1].line_number, 3);
+    assert_eq!(tokens[2].token_type, TokenType::DocComment);
+    assert_eq!(tokens[2].lexeme(), "///");
+}
+
+#[test]
+fn scanner_preserves_block_comments_on_request() {
+    let mut scanner = Scanner::create("/* a /* b */ */+");
+    scanner.preserve_comments();
+    
+    let comment = scanner.scan_token();
+    
+    assert_eq!(comment.token_type, TokenType::Comment);
+    assert_eq!(comment.lexeme(), "/* a /* b */ */");
+    assert_eq!(scanner.scan_token().token_type, TokenType::Plus);
+}
+
+#[test]
+fn scanner_reports_byte_offsets_and_columns() {
+    let source = "\u{a7}\n  (\"caf\u{e9}\" +\n\t42)";
+    let mut scanner = Scanner::create(source);
+    let tokens: Vec<Token> = (0..5).map(|_| scanner.scan_token()).collect();
+    
+    assert_eq!(tokens[0].token_type, TokenType::Error("Unexpected character"));
+    assert_eq!((tokens[0].lexeme_start(), tokens[0].lexeme_end()), (0, 2));
+    assert_eq!((tokens[1].lexeme_start(), tokens[1].column()), (5, 3));
+    assert_eq!((tokens[2].lexeme_start(), tokens[2].column()), (6, 4));
+    assert_eq!(tokens[3].column(), 11);
+    assert_eq!((tokens[4].lexeme_start(), tokens[4].column()), (17, 2));
+    assert_eq!(&source[tokens[4].lexeme_start()..tokens[4].lexeme_end()], "42");
+}
+
+#[test]
+fn scanner_borrows_lexemes_and_plain_string_values() {
+    let source = String::from("\"plain\" \"esc\\taped\"");
+    let tokens = tokenize(&source);
+    
+    assert!(::std::ptr::eq(tokens[0].lexeme().as_ptr(), source.as_ptr()));
+    assert!(matches!(tokens[0].value, Some(Cow::Borrowed("plain"))));
+    assert!(matches!(tokens[1].value, Some(Cow::Owned(_))));
+    assert_eq!(tokens[1].value(), Some("esc\taped"));
 }
 
-fn tokenize(corpus: &str) -> Vec<Token> {
-    let mut chars = corpus.chars();
-    let mut scanner = Scanner::create(&mut chars);
-    let mut tokens : Vec<Token> = Vec::new();
-    loop {
-        let token = scanner.next();
-        let token_type = token.token_type;
-        tokens.push(token);
+#[test]
+fn scanner_rescans_only_around_an_edit() {
+    let old_source = "1 + 2;\n\"a ${b} c\";\n3 * 4;\n5 - 6;\n";
+    let edit = Edit::create(12..13, "b + 1");
+    let new_source = edit.apply(old_source);
+    
+    let old_tokens = tokenize(old_source);
+    let (tokens, changed) = Scanner::create(&new_source).rescan(&old_tokens, &edit);
+    
+    assert_eq!(tokens, tokenize(&new_source));
+    assert_eq!(changed, 4..9);
+    assert_eq!((tokens[9].token_type, tokens[9].lexeme_start()), (TokenType::SemiColon, old_tokens[7].lexeme_start() + 4));
+}
+
+// Pieces that put the scanner in every state: inside strings,
+// interpolations and nested comments, part way through numbers and
+// identifiers, and before "\r\n".
+const FRAGMENTS: &[&str] = &[
+    "1", "0x1f", "2.5e", "_", "x", "\u{e9}", "e\u{301}", "\u{430}", "and", " ", "\t", "\n", "\r", "\r\n",
+    "\"", "\\", "\\n", "$", "${", "{", "}", "(", ")", "/", "//", "///", "/*", "*/", "*", "+", "-", ".", ";", "=", "@"
+];
+
+fn source_strategy() -> impl Strategy<Value = String> {
+    prop::collection::vec(prop::sample::select(FRAGMENTS), 0..40).prop_map(|fragments| fragments.concat())
+}
+
+// A source, an edit to it on character boundaries and whether to keep
+// trivia.
+fn edit_strategy() -> impl Strategy<Value = (String, usize, usize, String, bool)> {
+    (source_strategy(), any::<prop::sample::Index>(), any::<prop::sample::Index>(), source_strategy(), any::<bool>())
+        .prop_map(|(source, first, second, replacement, trivia)| {
+            let boundaries: Vec<usize> = source.char_indices().map(|(i, _)| i).chain(Some(source.len())).collect();
+            let (first, second) = (first.get(&boundaries), second.get(&boundaries));
+            (source.clone(), *first.min(second), *first.max(second), replacement.chars().take(6).collect(), trivia)
+        })
+}
+
+proptest! {
+    #[test]
+    fn scanner_rescans_like_a_full_scan((source, start, end, replacement, trivia) in edit_strategy()) {
+        let edit = Edit::create(start..end, &replacement);
+        let new_source = edit.apply(&source);
+        let create = |text| {
+            let mut scanner = Scanner::create(text);
+            if trivia {
+                scanner.preserve_trivia();
+            }
+            scanner
+        };
         
-        if token_type == TokenType::EndOfFile {
-            break;
-        }
+        let old_tokens: Vec<Token> = create(&source).collect();
+        let (tokens, changed) = create(&new_source).rescan(&old_tokens, &edit);
+        let expected: Vec<Token> = create(&new_source).collect();
         
-        if let TokenType::Error(_) = token_type {
-            break;
+        prop_assert_eq!(&tokens, &expected);
+        
+        // Outside the changed range, tokens are the old ones in the same
+        // order.
+        let unchanged_after = tokens.len() - changed.end;
+        prop_assert!(changed.start + unchanged_after <= old_tokens.len());
+        for i in 0..changed.start {
+            prop_assert_eq!(tokens[i].token_type, old_tokens[i].token_type);
+            prop_assert_eq!(tokens[i].lexeme(), old_tokens[i].lexeme());
+        }
+        for i in 0..unchanged_after {
+            let (new, old) = (&tokens[tokens.len() - 1 - i], &old_tokens[old_tokens.len() - 1 - i]);
+            prop_assert_eq!(new.token_type, old.token_type);
+            prop_assert_eq!(new.lexeme(), old.lexeme());
+            prop_assert_eq!(new.value(), old.value());
         }
-    };
+    }
+}
+
+// A throughput benchmark rather than a check; run it with
+// `cargo test --release scanner_throughput -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn scanner_throughput() {
+    use std::time::Instant;
+    
+    let line = "(1_024 + 0xff) * 2.5e-3 / -\"interpolated ${42} text\" // comment\n/* block */ { 7; }\n";
+    let source = line.repeat(8 * 1024 * 1024 / line.len());
+    
+    let started = Instant::now();
+    let mut scanner = Scanner::create(&source);
+    let mut count = 0;
+    while scanner.scan_token().token_type != TokenType::EndOfFile {
+        count += 1;
+    }
+    let elapsed = started.elapsed();
+    
+    println!("scanned {} tokens from {} bytes in {:?} ({:.1} MB/s)",
+        count, source.len(), elapsed, source.len() as f64 / 1e6 / elapsed.as_secs_f64());
+}
+
+fn test_scanner(corpus: &str, expected_tokens: &[TokenType]) {
+    let actual_tokens: Vec<TokenType> = tokenize(corpus)
+        .iter()
+        .map(|t| t.token_type)
+        .collect();
     
-    return tokens;
+    assert_slice_eq(actual_tokens.as_slice(), expected_tokens);
+}
+
+fn tokenize_trivia<'a>(corpus: &'a str) -> Vec<Token<'a>> {
+    let mut scanner = Scanner::create(corpus);
+    scanner.preserve_trivia();
+    return scanner.collect();
 }
 
 fn assert_slice_eq<T: Eq + Debug>(actual: &[T], expected: &[T]) {
@@ -131,4 +515,3 @@ fn assert_slice_eq<T: Eq + Debug>(actual: &[T], expected: &[T]) {
 
     assert_eq!(actual.len(), expected.len());
 }
-
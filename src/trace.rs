@@ -0,0 +1,137 @@
+use std::io::Write;
+use chunks::Instruction;
+use serde_json;
+use serde_json::Value;
+use debug::{json_value, opcode_name};
+use runtime::LoxValue;
+
+// Writes one JSON object per executed instruction to its own sink, so a
+// trace never interleaves with the program's output.
+pub struct Tracer {
+    sink: Box<dyn Write>,
+    function: Option<String>,
+    lines: Option<(usize, usize)>
+}
+
+pub struct TraceStep<'a> {
+    pub frame: &'a str,
+    pub ip: usize,
+    pub line: usize,
+    pub instruction: &'a Instruction,
+    pub stack: &'a [LoxValue]
+}
+
+impl Tracer {
+    pub fn create(sink: Box<dyn Write>) -> Tracer {
+        Tracer {
+            sink,
+            function: None,
+            lines: None
+        }
+    }
+    
+    // Only trace instructions executing in the named function.
+    pub fn filter_function(&mut self, name: &str) {
+        self.function = Some(name.to_owned());
+    }
+    
+    // Only trace instructions compiled from lines first through last inclusive.
+    pub fn filter_lines(&mut self, first: usize, last: usize) {
+        self.lines = Some((first, last));
+    }
+    
+    pub fn step(&mut self, step: &TraceStep) {
+        if !self.accepts(step) {
+            return;
+        }
+        
+        let stack: Vec<Value> = step.stack.iter().map(json_value).collect();
+        let entry = json!({
+            "frame": step.frame,
+            "ip": step.ip,
+            "line": step.line,
+            "opcode": opcode_name(step.instruction),
+            "operands": &step.instruction.as_bytecode()[1..],
+            "stack": stack
+        });
+
+        // A trace is best effort; a failing sink shouldn't stop the program.
+        let _ = serde_json::to_writer(&mut self.sink, &entry);
+        let _ = writeln!(self.sink);
+    }
+    
+    pub fn flush(&mut self) {
+        let _ = self.sink.flush();
+    }
+    
+    fn accepts(&self, step: &TraceStep) -> bool {
+        if let Some(ref function) = self.function {
+            if function != step.frame {
+                return false;
+            }
+        }
+        
+        if let Some((first, last)) = self.lines {
+            if step.line < first || step.line > last {
+                return false;
+            }
+        }
+        
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::SharedBuffer;
+
+    fn traced(configure: &dyn Fn(&mut Tracer)) -> String {
+        let buffer = SharedBuffer::default();
+        let mut tracer = Tracer::create(Box::new(buffer.clone()));
+        configure(&mut tracer);
+
+        let stack = [LoxValue::number(1.0), LoxValue::boolean(true)];
+        for &(frame, line) in &[("script", 1), ("script", 2), ("other", 2), ("script", 3)] {
+            tracer.step(&TraceStep {
+                frame,
+                ip: line * 2,
+                line,
+                instruction: &Instruction::Constant(7),
+                stack: &stack
+            });
+        }
+
+        String::from_utf8(buffer.contents()).unwrap()
+    }
+
+    #[test]
+    fn tracer_writes_a_json_line_per_step() {
+        let output = traced(&|_| { });
+
+        assert_eq!(output.lines().count(), 4);
+        assert_eq!(output.lines().next().unwrap(),
+            "{\"frame\":\"script\",\"ip\":2,\"line\":1,\"opcode\":\"CONST\",\"operands\":[7],\"stack\":[{\"type\":\"number\",\"value\":1.0},{\"type\":\"boolean\",\"value\":true}]}");
+    }
+
+    #[test]
+    fn tracer_filters_by_function() {
+        let output = traced(&|tracer| tracer.filter_function("other"));
+
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.starts_with("{\"frame\":\"other\""));
+    }
+
+    #[test]
+    fn tracer_filters_by_line_range() {
+        let output = traced(&|tracer| {
+            tracer.filter_function("script");
+            tracer.filter_lines(2, 3);
+        });
+
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"line\":2"));
+        assert!(lines[1].contains("\"line\":3"));
+    }
+}
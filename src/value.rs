@@ -0,0 +1,379 @@
+use std::fmt::{Debug, Formatter, Result};
+
+pub use self::representation::LoxValue;
+
+// Both representations keep their layout private behind the same
+// constructors and accessors, so code built against one builds against the
+// other.
+#[cfg(not(feature = "nan-boxing"))]
+mod representation {
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    pub struct LoxValue(Value);
+
+    #[derive(Clone)]
+    enum Value {
+        Number(f64),
+        Boolean(bool),
+        Nil,
+        String(Rc<str>)
+    }
+
+    impl LoxValue {
+        pub fn number(value: f64) -> LoxValue {
+            LoxValue(Value::Number(value))
+        }
+
+        pub fn boolean(value: bool) -> LoxValue {
+            LoxValue(Value::Boolean(value))
+        }
+
+        pub fn nil() -> LoxValue {
+            LoxValue(Value::Nil)
+        }
+
+        pub fn string(text: &str) -> LoxValue {
+            LoxValue(Value::String(Rc::from(text)))
+        }
+
+        pub fn as_number(&self) -> Option<f64> {
+            match self.0 {
+                Value::Number(value) => Some(value),
+                _ => None
+            }
+        }
+
+        pub fn as_boolean(&self) -> Option<bool> {
+            match self.0 {
+                Value::Boolean(value) => Some(value),
+                _ => None
+            }
+        }
+
+        pub fn is_nil(&self) -> bool {
+            matches!(self.0, Value::Nil)
+        }
+
+        pub fn as_string(&self) -> Option<&str> {
+            match self.0 {
+                Value::String(ref text) => Some(text),
+                _ => None
+            }
+        }
+    }
+}
+
+// Every value lives in a single u64. Any bit pattern which isn't a quiet NaN
+// is an ordinary f64; quiet NaNs with a small tag in the low bits encode nil
+// and the booleans. Quiet NaNs with the sign bit set are reserved for object
+// pointers, whose 48 bit address fits in the remaining mantissa bits.
+//
+// That holds for user space on 64 bit x86 and ARM, where addresses above
+// 48 bits are only handed out to processes that ask for them, so other
+// targets are refused at compile time rather than checked on every string.
+#[cfg(all(feature = "nan-boxing", not(all(target_pointer_width = "64", any(target_arch = "x86_64", target_arch = "aarch64")))))]
+compile_error!("The nan-boxing feature needs 48 bit pointers, as on x86_64 and aarch64");
+
+#[cfg(feature = "nan-boxing")]
+mod representation {
+    use std::marker::PhantomData;
+    use std::rc::Rc;
+
+    const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const POINTER_MASK: u64 = 0x0000_ffff_ffff_ffff;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const NIL: u64 = QUIET_NAN | TAG_NIL;
+    const FALSE: u64 = QUIET_NAN | TAG_FALSE;
+    const TRUE: u64 = QUIET_NAN | TAG_TRUE;
+
+    // A string value owns one count of its Rc, which the marker keeps from
+    // being sent to another thread.
+    pub struct LoxValue(u64, PhantomData<Rc<String>>);
+
+    impl LoxValue {
+        pub fn number(value: f64) -> LoxValue {
+            // Arithmetic can produce NaNs with arbitrary payloads, which must
+            // not be mistaken for one of the tagged encodings.
+            if value.is_nan() {
+                LoxValue(f64::NAN.to_bits(), PhantomData)
+            } else {
+                LoxValue(value.to_bits(), PhantomData)
+            }
+        }
+
+        pub fn boolean(value: bool) -> LoxValue {
+            if value {
+                LoxValue(TRUE, PhantomData)
+            } else {
+                LoxValue(FALSE, PhantomData)
+            }
+        }
+
+        pub fn nil() -> LoxValue {
+            LoxValue(NIL, PhantomData)
+        }
+
+        pub fn string(text: &str) -> LoxValue {
+            let pointer = Rc::into_raw(Rc::new(text.to_string())) as u64;
+            LoxValue(QUIET_NAN | SIGN_BIT | pointer, PhantomData)
+        }
+
+        pub fn as_number(&self) -> Option<f64> {
+            if self.0 & QUIET_NAN != QUIET_NAN {
+                Some(f64::from_bits(self.0))
+            } else {
+                None
+            }
+        }
+
+        pub fn as_boolean(&self) -> Option<bool> {
+            match self.0 {
+                TRUE => Some(true),
+                FALSE => Some(false),
+                _ => None
+            }
+        }
+
+        pub fn is_nil(&self) -> bool {
+            self.0 == NIL
+        }
+
+        fn is_object(&self) -> bool {
+            self.0 & (QUIET_NAN | SIGN_BIT) == QUIET_NAN | SIGN_BIT
+        }
+
+        pub fn as_string(&self) -> Option<&str> {
+            self.as_pointer().map(|pointer| {
+                // Strings are the only objects, and this value holds a count
+                // of the Rc, so the string lives at least as long as it does.
+                let string = unsafe { &*pointer };
+                string.as_str()
+            })
+        }
+
+        fn as_pointer(&self) -> Option<*const String> {
+            if self.is_object() {
+                Some((self.0 & POINTER_MASK) as *const String)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl Clone for LoxValue {
+        fn clone(&self) -> LoxValue {
+            if let Some(pointer) = self.as_pointer() {
+                unsafe { Rc::increment_strong_count(pointer) };
+            }
+            LoxValue(self.0, PhantomData)
+        }
+    }
+
+    impl Drop for LoxValue {
+        fn drop(&mut self) {
+            if let Some(pointer) = self.as_pointer() {
+                unsafe { Rc::decrement_strong_count(pointer) };
+            }
+        }
+    }
+}
+
+impl LoxValue {
+    
+    pub fn negate(&self) -> Option<LoxValue> {
+        self.as_number().map(|value| LoxValue::number(-value))
+    }
+    
+    pub fn add(&self, other: &LoxValue) -> Option<LoxValue> {
+        if let (Some(left), Some(right)) = (self.as_string(), other.as_string()) {
+            return Some(LoxValue::string(&[left, right].concat()));
+        }
+
+        LoxValue::binary_numbers_action(self, other, &|left, right| left + right)
+    }
+
+    // Converts the value to a string the way it would be printed.
+    pub fn stringify(&self) -> LoxValue {
+        match self.as_string() {
+            Some(_) => self.clone(),
+            None => LoxValue::string(&format!("{:?}", self))
+        }
+    }
+
+    // Only nil and false are falsey.
+    pub fn not(&self) -> LoxValue {
+        LoxValue::boolean(self.is_nil() || self.as_boolean() == Some(false))
+    }
+
+    // Values of different types are never equal, and numbers compare as
+    // IEEE doubles, so NaN is not even equal to itself.
+    pub fn equals(&self, other: &LoxValue) -> LoxValue {
+        let equal = if let (Some(left), Some(right)) = (self.as_number(), other.as_number()) {
+            left == right
+        } else if let (Some(left), Some(right)) = (self.as_boolean(), other.as_boolean()) {
+            left == right
+        } else if let (Some(left), Some(right)) = (self.as_string(), other.as_string()) {
+            left == right
+        } else {
+            self.is_nil() && other.is_nil()
+        };
+
+        LoxValue::boolean(equal)
+    }
+
+    pub fn greater(&self, other: &LoxValue) -> Option<LoxValue> {
+        LoxValue::binary_numbers(self, other).map(|(left, right)| LoxValue::boolean(left > right))
+    }
+
+    pub fn less(&self, other: &LoxValue) -> Option<LoxValue> {
+        LoxValue::binary_numbers(self, other).map(|(left, right)| LoxValue::boolean(left < right))
+    }
+
+    pub fn subtract(&self, other: &LoxValue) -> Option<LoxValue> {
+        LoxValue::binary_numbers_action(self, other, &|left, right| left - right)
+    }
+
+    pub fn multiply(&self, other: &LoxValue) -> Option<LoxValue> {
+        LoxValue::binary_numbers_action(self, other, &|left, right| left * right)
+    }
+
+    pub fn divide(&self, other: &LoxValue) -> Option<LoxValue> {
+        LoxValue::binary_numbers_action(self, other, &|left, right| left / right)
+    }
+    
+    fn binary_numbers_action(left: &LoxValue, right: &LoxValue, action: &dyn Fn(f64, f64) -> f64)
+        -> Option<LoxValue> {
+        LoxValue::binary_numbers(left, right)
+            .map(|tuple| LoxValue::number(action(tuple.0, tuple.1)))
+    }
+
+    fn binary_numbers(left: &LoxValue, right: &LoxValue) -> Option<(f64, f64)> {
+        if let (Some(left_value), Some(right_value)) = (left.as_number(), right.as_number()) {
+            Some((left_value, right_value))
+        } else {
+            None
+        }
+    }
+}
+
+impl Debug for LoxValue {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        if let Some(value) = self.as_number() {
+            write!(f, "{}", value)
+        } else if let Some(value) = self.as_boolean() {
+            write!(f, "{}", value)
+        } else if self.is_nil() {
+            write!(f, "nil")
+        } else if let Some(text) = self.as_string() {
+            write!(f, "{}", text)
+        } else {
+            write!(f, "")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_round_trips_numbers() {
+        for &number in &[0.0, -0.0, 1.5, -42.0, f64::INFINITY, f64::NEG_INFINITY, f64::MAX] {
+            assert_eq!(LoxValue::number(number).as_number().map(f64::to_bits), Some(number.to_bits()));
+        }
+    }
+
+    #[test]
+    fn value_keeps_nan_a_number() {
+        let nan = LoxValue::number(0.0).divide(&LoxValue::number(0.0)).unwrap();
+
+        assert!(nan.as_number().unwrap().is_nan());
+        assert!(!nan.is_nil());
+        assert_eq!(nan.as_boolean(), None);
+    }
+
+    #[test]
+    fn value_distinguishes_booleans_and_nil() {
+        assert_eq!(LoxValue::boolean(true).as_boolean(), Some(true));
+        assert_eq!(LoxValue::boolean(false).as_boolean(), Some(false));
+        assert_eq!(LoxValue::boolean(true).as_number(), None);
+        assert!(LoxValue::nil().is_nil());
+        assert_eq!(LoxValue::nil().as_boolean(), None);
+    }
+
+    #[test]
+    fn value_arithmetic_only_applies_to_numbers() {
+        let two = LoxValue::number(2.0);
+        let yes = LoxValue::boolean(true);
+
+        assert_eq!(two.add(&two).and_then(|v| v.as_number()), Some(4.0));
+        assert_eq!(two.negate().and_then(|v| v.as_number()), Some(-2.0));
+        assert!(two.add(&yes).is_none());
+        assert!(yes.negate().is_none());
+        assert_eq!(LoxValue::number(1.0).divide(&LoxValue::number(0.0)).and_then(|v| v.as_number()), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn value_compares_by_type_and_content() {
+        let one = LoxValue::number(1.0);
+        let nan = LoxValue::number(f64::NAN);
+        let text = LoxValue::string("1");
+
+        assert_eq!(one.equals(&LoxValue::number(1.0)).as_boolean(), Some(true));
+        assert_eq!(nan.equals(&nan).as_boolean(), Some(false));
+        assert_eq!(one.equals(&text).as_boolean(), Some(false));
+        assert_eq!(text.equals(&LoxValue::string("1")).as_boolean(), Some(true));
+        assert_eq!(LoxValue::nil().equals(&LoxValue::nil()).as_boolean(), Some(true));
+        assert_eq!(LoxValue::nil().equals(&LoxValue::boolean(false)).as_boolean(), Some(false));
+        assert_eq!(one.less(&LoxValue::number(2.0)).and_then(|v| v.as_boolean()), Some(true));
+        assert_eq!(one.greater(&nan).and_then(|v| v.as_boolean()), Some(false));
+        assert!(one.less(&text).is_none());
+    }
+
+    #[test]
+    fn value_treats_only_nil_and_false_as_falsey() {
+        assert_eq!(LoxValue::nil().not().as_boolean(), Some(true));
+        assert_eq!(LoxValue::boolean(false).not().as_boolean(), Some(true));
+        assert_eq!(LoxValue::boolean(true).not().as_boolean(), Some(false));
+        assert_eq!(LoxValue::number(0.0).not().as_boolean(), Some(false));
+        assert_eq!(LoxValue::string("").not().as_boolean(), Some(false));
+    }
+
+    #[test]
+    fn value_concatenates_and_stringifies_strings() {
+        let hello = LoxValue::string("Hello, ");
+        let world = LoxValue::string("w\u{f6}rld");
+
+        assert_eq!(hello.add(&world).unwrap().as_string(), Some("Hello, w\u{f6}rld"));
+        assert!(hello.add(&LoxValue::number(1.0)).is_none());
+        assert_eq!(hello.as_number(), None);
+        assert!(!hello.is_nil());
+        assert_eq!(LoxValue::number(2.5).stringify().as_string(), Some("2.5"));
+        assert_eq!(LoxValue::nil().stringify().as_string(), Some("nil"));
+        assert_eq!(hello.stringify().as_string(), Some("Hello, "));
+        assert_eq!(LoxValue::number(1.0).as_string(), None);
+    }
+
+    #[test]
+    fn value_keeps_strings_alive_while_any_copy_remains() {
+        let first = LoxValue::string("shared");
+        let second = first.clone();
+
+        drop(first);
+
+        assert_eq!(second.as_string(), Some("shared"));
+        assert_eq!(second.clone().stringify().as_string(), Some("shared"));
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn value_fits_in_a_single_word() {
+        assert_eq!(::std::mem::size_of::<LoxValue>(), 8);
+    }
+}